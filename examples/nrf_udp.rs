@@ -0,0 +1,120 @@
+//! MQTT-SN over plain UDP on an nRF9160, driven by the embassy executor.
+//! Board bring-up (modem interrupts and shared memory) follows the nrf-modem
+//! documentation and is left out, this shows the client side. It runs
+//! non-secure, see memory.x, and embassy-nrf drives time from RTC1.
+//!
+//! rustup target add thumbv8m.main-none-eabihf
+//! cargo build --example nrf_udp --target thumbv8m.main-none-eabihf
+#![no_std]
+#![no_main]
+
+use defmt_rtt as _;
+use panic_probe as _;
+
+use embassy_executor::Spawner;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::PubSubChannel;
+use embassy_time::{Duration, Timer};
+use mqttsn_client::mqttsn::{MqttSnClient, MqttMessage};
+use mqttsn_client::socket::{SendBytes, ReceiveBytes, Connect, Reconnect, SocketError};
+use no_std_net::{SocketAddr, SocketAddrV4, Ipv4Addr};
+use nrf_modem::{ConnectionPreference, SystemMode, UdpSocket};
+
+// Application -> client, messages to publish
+static MQTT_SEND: PubSubChannel<CriticalSectionRawMutex, MqttMessage, 4, 1, 1> = PubSubChannel::new();
+// Client -> application, received messages
+static MQTT_RECV: PubSubChannel<CriticalSectionRawMutex, MqttMessage, 4, 1, 1> = PubSubChannel::new();
+
+fn gateway() -> SocketAddr {
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 1884))
+}
+
+/// A UDP socket talking to a single gateway
+struct Udp {
+    socket: UdpSocket,
+    gateway: SocketAddr,
+}
+
+impl SendBytes for Udp {
+    async fn send(&mut self, buf: &[u8]) -> Result<(), SocketError> {
+        self.socket.send_to(buf, self.gateway).await?;
+        Ok(())
+    }
+}
+
+impl ReceiveBytes for Udp {
+    async fn recv<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], SocketError> {
+        Ok(self.socket.receive_from(buf).await?.0)
+    }
+
+    async fn recv_from<'a>(
+        &mut self, buf: &'a mut [u8]
+    ) -> Result<(&'a mut [u8], SocketAddr), SocketError> {
+        Ok(self.socket.receive_from(buf).await?)
+    }
+}
+
+impl Connect for Udp {
+    async fn connect(&mut self, addr: SocketAddr) -> Result<(), SocketError> {
+        self.gateway = addr;
+        Ok(())
+    }
+}
+
+impl Reconnect for Udp {
+    // Nothing to re-establish for plain UDP
+    async fn reconnect(&mut self) -> Result<(), SocketError> {
+        Ok(())
+    }
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    // Starts the RTC1 time driver behind embassy-time
+    let _peripherals = embassy_nrf::init(Default::default());
+    nrf_modem::init(SystemMode {
+        lte_support: true,
+        nbiot_support: false,
+        gnss_support: false,
+        preference: ConnectionPreference::Lte,
+    }).await.unwrap();
+
+    let socket = UdpSocket::bind(
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+    ).await.unwrap();
+    let udp = Udp { socket, gateway: gateway() };
+
+    let mut client = MqttSnClient::new(
+        "nrf-example",
+        MQTT_SEND.dyn_subscriber().unwrap(),
+        MQTT_RECV.dyn_publisher().unwrap(),
+        udp
+    ).unwrap();
+    client.connect(60).await.unwrap();
+    client.subscribe("example/cmd", 0).await.unwrap();
+    defmt::info!("MQTT-SN connected");
+
+    spawner.spawn(producer()).unwrap();
+    spawner.spawn(consumer()).unwrap();
+    // Publishes what the producer queues, sleeping 60 seconds in between
+    client.run(60).await;
+}
+
+#[embassy_executor::task]
+async fn producer() {
+    let publisher = MQTT_SEND.dyn_publisher().unwrap();
+    loop {
+        Timer::after(Duration::from_secs(30)).await;
+        let msg = MqttMessage::new("example/status", "alive", Some(1), false).unwrap();
+        publisher.publish_immediate(msg);
+    }
+}
+
+#[embassy_executor::task]
+async fn consumer() {
+    let mut subscriber = MQTT_RECV.dyn_subscriber().unwrap();
+    loop {
+        let msg = subscriber.next_message_pure().await;
+        defmt::info!("received on {}", msg.topic.as_str());
+    }
+}
@@ -0,0 +1,7 @@
+/* nRF9160 running non-secure next to the secure partition manager, as
+   nrf-modem requires. Only used to link the nrf_udp example. */
+MEMORY
+{
+  FLASH : ORIGIN = 0x00050000, LENGTH = 704K
+  RAM   : ORIGIN = 0x20018000, LENGTH = 160K
+}
@@ -1,5 +1,6 @@
 use nrf_modem::DtlsSocket;
-use crate::socket::{SocketError, SendBytes, ReceiveBytes};
+use no_std_net::SocketAddr;
+use crate::socket::{SocketError, SendBytes, ReceiveBytes, Connect, Reconnect};
 
 impl From<nrf_modem::Error> for SocketError {
     fn from(_e: nrf_modem::Error) -> SocketError {
@@ -14,6 +15,21 @@ impl DtlsSession {
     }
 }
 
+impl Reconnect for DtlsSession {
+    /// The modem keeps the DTLS session up by itself, nothing to redo
+    async fn reconnect(&mut self) -> Result<(), SocketError> {
+        Ok(())
+    }
+}
+
+impl Connect for DtlsSession {
+    /// The modem's DTLS socket stays tied to the peer it was opened with,
+    /// a new gateway needs a new session
+    async fn connect(&mut self, _addr: SocketAddr) -> Result<(), SocketError> {
+        Err(SocketError::Generic)
+    }
+}
+
 impl SendBytes for DtlsSession {
     async fn send(&mut self, buf: &[u8]) -> Result<(), SocketError> {
         self.0.send(buf).await?;
@@ -25,4 +41,10 @@ impl ReceiveBytes for DtlsSession {
     async fn recv<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], SocketError> {
         Ok(self.0.receive_from(buf).await?.0)
     }
+
+    async fn recv_from<'a>(
+        &mut self, buf: &'a mut [u8]
+    ) -> Result<(&'a mut [u8], SocketAddr), SocketError> {
+        Ok(self.0.receive_from(buf).await?)
+    }
 }
\ No newline at end of file
@@ -1,14 +1,17 @@
 use tokio::net::UdpSocket;
 use tokio_dtls_stream_sink::{Client, Session};
-use openssl::ssl::{SslContext, SslMethod, SslRef};
+use openssl::ssl::{SslContext, SslContextBuilder, SslMethod, SslRef, SslVerifyMode};
+use openssl::x509::X509;
+use openssl::pkey::PKey;
 use openssl::error::ErrorStack;
 use openssl_errors::{openssl_errors, put_error};
 use log::*;
 use std::error;
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
 use serde_yaml::Value;
-use crate::socket::{SocketError, SendBytes, ReceiveBytes};
+use crate::socket::{SocketError, SendBytes, ReceiveBytes, Connect, Reconnect};
 use std::ffi::CString;
+use std::fmt;
 
 openssl_errors! {
     pub library DtlsErr("DTLS errors") {
@@ -95,23 +98,92 @@ fn get_server_psk(
     Ok(len)
 }
 
+/// Why a DTLS handshake failed
+#[derive(Debug)]
+pub enum DtlsError {
+    /// The gateway didn't complete a PSK handshake, most likely because it
+    /// doesn't know the identity or has a different key
+    PskMismatch(String),
+    /// The gateway's certificate doesn't chain to the configured CA, or
+    /// it rejected ours
+    UntrustedCertificate(String),
+}
+
+impl fmt::Display for DtlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DtlsError::PskMismatch(e) => write!(f, "PSK handshake failed, check identity and key: {}", e),
+            DtlsError::UntrustedCertificate(e) => write!(f, "certificate handshake failed: {}", e),
+        }
+    }
+}
+
+impl error::Error for DtlsError {}
+
+enum Auth {
+    Psk,
+    Certificate,
+}
+
 pub struct DtlsSocket {
     client: Client,
     context: SslContext,
+    auth: Auth,
 }
 
 impl DtlsSocket {
+    /// PSK socket reading its identity and key from `key.yml`
     pub async fn new() -> Result<Self, Box<dyn error::Error>> {
+        let mut context = SslContext::builder(SslMethod::dtls())?;
+        context.set_psk_client_callback(get_server_psk);
+        Self::with_context(context.build(), Auth::Psk).await
+    }
+
+    /// PSK socket with the given identity and key
+    pub async fn with_psk(identity: &str, key: &[u8]) -> Result<Self, Box<dyn error::Error>> {
+        let identity = CString::new(identity)?.into_bytes_with_nul();
+        let key = key.to_vec();
+        let mut context = SslContext::builder(SslMethod::dtls())?;
+        context.set_cipher_list("PSK")?;
+        context.set_psk_client_callback(move |_ssl, _hint, client_id, psk| {
+            if identity.len() > client_id.len() || key.len() > psk.len() {
+                put_error!(DtlsErr::FIND_PRIVATE_KEY, DtlsErr::ID_NOT_VALID);
+                return Err(ErrorStack::get());
+            }
+            client_id[..identity.len()].copy_from_slice(&identity);
+            psk[..key.len()].copy_from_slice(&key);
+            Ok(key.len())
+        });
+        Self::with_context(context.build(), Auth::Psk).await
+    }
 
+    /// Certificate socket accepting only gateways whose certificate chains
+    /// to the PEM encoded CA
+    pub async fn with_ca(ca_pem: &[u8]) -> Result<Self, Box<dyn error::Error>> {
+        let context = ca_context(ca_pem)?;
+        Self::with_context(context.build(), Auth::Certificate).await
+    }
+
+    /// Like `with_ca`, also authenticating the client with a PEM encoded
+    /// certificate and private key
+    pub async fn with_client_cert(
+        ca_pem: &[u8], cert_pem: &[u8], key_pem: &[u8]
+    ) -> Result<Self, Box<dyn error::Error>> {
+        let mut context = ca_context(ca_pem)?;
+        context.set_certificate(&X509::from_pem(cert_pem)?)?;
+        context.set_private_key(&PKey::private_key_from_pem(key_pem)?)?;
+        context.check_private_key()?;
+        Self::with_context(context.build(), Auth::Certificate).await
+    }
+
+    async fn with_context(context: SslContext, auth: Auth) -> Result<Self, Box<dyn error::Error>> {
         let sock = UdpSocket::bind("0.0.0.0:0").await?;
         let client = Client::new(sock);
-        let mut context = SslContext::builder(SslMethod::dtls())?;
-        context.set_psk_client_callback(get_server_psk);
-        let context = context.build();
 
         Ok(Self {
             client,
-            context
+            context,
+            auth
         })
     }
 
@@ -119,11 +191,80 @@ impl DtlsSocket {
             addr: impl ToSocketAddrs,
         ) -> Result<DtlsSession, Box<dyn error::Error>> {
         info!("Connecting DTLS");
-        Ok(DtlsSession(self.client.connect(addr, Some(self.context.clone())).await?))
+        let peer = addr.to_socket_addrs()?.next().ok_or("no gateway address")?;
+        let session = match self.client.connect(peer, Some(self.context.clone())).await {
+            Ok(session) => session,
+            Err(e) => return Err(self.handshake_error(e.into())),
+        };
+        Ok(DtlsSession(session, peer))
+    }
+
+    /// Name the cause of a failed connect when OpenSSL reports one this
+    /// auth mode explains. Anything else, like an unreachable gateway or a
+    /// timeout, is passed on as is.
+    fn handshake_error(&self, e: Box<dyn error::Error>) -> Box<dyn error::Error> {
+        let reasons = ssl_reasons(e.as_ref());
+        let reported = |known: &[&str]| {
+            reasons.iter().any(|reason| known.iter().any(|k| reason.contains(k)))
+        };
+        match self.auth {
+            Auth::Psk if reported(PSK_FAILURES) => DtlsError::PskMismatch(e.to_string()).into(),
+            Auth::Certificate if reported(CERTIFICATE_FAILURES) => {
+                DtlsError::UntrustedCertificate(e.to_string()).into()
+            },
+            _ => e,
+        }
     }
 }
 
-pub struct DtlsSession(Session);
+/// OpenSSL reasons for a PSK handshake with the wrong identity or key: the
+/// gateway either doesn't know the identity or can't decrypt our Finished
+const PSK_FAILURES: &[&str] = &[
+    "unknown psk identity",
+    "bad record mac",
+    "decrypt error",
+    "decryption failed",
+];
+
+/// OpenSSL reasons for a certificate that failed verification, either the
+/// gateway's on our side or ours on the gateway's, which it reports with an
+/// alert
+const CERTIFICATE_FAILURES: &[&str] = &[
+    "certificate verify failed",
+    "alert bad certificate",
+    "alert unknown ca",
+    "alert certificate unknown",
+    "alert certificate required",
+];
+
+/// Reasons of the OpenSSL errors anywhere in the source chain of `e`
+fn ssl_reasons(e: &(dyn error::Error + 'static)) -> Vec<&'static str> {
+    let mut reasons = Vec::new();
+    let mut next = Some(e);
+    while let Some(e) = next {
+        let stack = e.downcast_ref::<openssl::ssl::Error>()
+            .and_then(|e| e.ssl_error())
+            .or_else(|| e.downcast_ref::<ErrorStack>());
+        if let Some(stack) = stack {
+            reasons.extend(stack.errors().iter().filter_map(|e| e.reason()));
+        }
+        // io::Error hides a wrapped error from source(), only get_ref has it
+        next = match e.downcast_ref::<std::io::Error>().and_then(|e| e.get_ref()) {
+            Some(inner) => Some(inner as &(dyn error::Error + 'static)),
+            None => e.source(),
+        };
+    }
+    reasons
+}
+
+fn ca_context(ca_pem: &[u8]) -> Result<SslContextBuilder, ErrorStack> {
+    let mut context = SslContext::builder(SslMethod::dtls())?;
+    context.cert_store_mut().add_cert(X509::from_pem(ca_pem)?)?;
+    context.set_verify(SslVerifyMode::PEER);
+    Ok(context)
+}
+
+pub struct DtlsSession(Session, SocketAddr);
 
 impl SendBytes for DtlsSession {
     async fn send(&mut self, buf: &[u8]) -> Result<(), SocketError> {
@@ -136,4 +277,73 @@ impl ReceiveBytes for DtlsSession {
     async fn recv<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], SocketError> {
         Ok(self.0.read(buf).await.map(|len| &mut buf[..len])?)
     }
+
+    async fn recv_from<'a>(
+        &mut self, buf: &'a mut [u8]
+    ) -> Result<(&'a mut [u8], SocketAddr), SocketError> {
+        Ok((self.recv(buf).await?, self.1))
+    }
+}
+
+/// A DTLS transport that can move between gateways, doing a fresh handshake
+/// with each one it is connected to.
+pub struct DtlsClient {
+    socket: DtlsSocket,
+    session: Option<DtlsSession>,
+    /// Gateway last connected to, kept when its handshake fails so that
+    /// `reconnect` can try it again
+    peer: Option<SocketAddr>,
+}
+
+impl DtlsClient {
+    pub fn new(socket: DtlsSocket) -> Self {
+        Self { socket, session: None, peer: None }
+    }
+
+    fn session(&mut self) -> Result<&mut DtlsSession, SocketError> {
+        self.session.as_mut().ok_or(SocketError::Generic)
+    }
+}
+
+impl Connect for DtlsClient {
+    async fn connect(&mut self, addr: SocketAddr) -> Result<(), SocketError> {
+        self.session = None;
+        self.peer = Some(addr);
+        let session = self.socket.connect(addr).await.map_err(|e| {
+            warn!("DTLS handshake with {} failed: {}", addr, e);
+            if e.is::<DtlsError>() {
+                SocketError::Handshake
+            } else {
+                SocketError::Generic
+            }
+        })?;
+        self.session = Some(session);
+        Ok(())
+    }
+}
+
+impl Reconnect for DtlsClient {
+    /// Handshake again with the current gateway
+    async fn reconnect(&mut self) -> Result<(), SocketError> {
+        let addr = self.peer.ok_or(SocketError::Generic)?;
+        self.connect(addr).await
+    }
+}
+
+impl SendBytes for DtlsClient {
+    async fn send(&mut self, buf: &[u8]) -> Result<(), SocketError> {
+        self.session()?.send(buf).await
+    }
+}
+
+impl ReceiveBytes for DtlsClient {
+    async fn recv<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], SocketError> {
+        self.session()?.recv(buf).await
+    }
+
+    async fn recv_from<'a>(
+        &mut self, buf: &'a mut [u8]
+    ) -> Result<(&'a mut [u8], SocketAddr), SocketError> {
+        self.session()?.recv_from(buf).await
+    }
 }
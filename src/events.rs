@@ -0,0 +1,84 @@
+use heapless::Deque;
+use crate::mqttsn::MqttSnClientError;
+
+/// Protocol event kept in the flight recorder. Packets are identified by
+/// their MQTT-SN message type byte.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "no_std", derive(defmt::Format))]
+pub enum Event {
+    Sent(u8),
+    Received(u8),
+    Retry(u8),
+    AckTimeout,
+    Error(MqttSnClientError),
+}
+
+/// Fixed capacity ring buffer of the most recent events
+pub struct EventLog<const N: usize> {
+    events: Deque<Event, N>
+}
+
+impl<const N: usize> EventLog<N> {
+    pub fn new() -> Self {
+        Self { events: Deque::new() }
+    }
+
+    pub fn record(&mut self, event: Event) {
+        if self.events.is_full() {
+            self.events.pop_front();
+        }
+        let _ = self.events.push_back(event);
+    }
+
+    /// Record a raw packet, sent or received
+    pub fn record_packet(&mut self, packet: &[u8], sent: bool) {
+        if let Some(msg_type) = msg_type(packet) {
+            self.record(if sent { Event::Sent(msg_type) } else { Event::Received(msg_type) });
+        }
+    }
+
+    /// Events from oldest to newest
+    pub fn iter(&self) -> impl Iterator<Item = &Event> {
+        self.events.iter()
+    }
+}
+
+/// Running totals since the client was created
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "no_std", derive(defmt::Format))]
+pub struct Metrics {
+    /// PUBLISH transmissions, retransmissions included
+    pub publishes_sent: u32,
+    /// Acknowledgements that completed a `send_ack`
+    pub acks_received: u32,
+    pub retransmissions: u32,
+    /// PINGREQ transmissions
+    pub pings: u32,
+    /// Received packets that failed to decode
+    pub parse_errors: u32,
+    /// Ack waits that ran out
+    pub timeouts: u32,
+}
+
+impl Metrics {
+    /// Count a raw packet being sent
+    pub fn record_sent(&mut self, packet: &[u8]) {
+        match msg_type(packet) {
+            Some(PUBLISH) => self.publishes_sent = self.publishes_sent.wrapping_add(1),
+            Some(PINGREQ) => self.pings = self.pings.wrapping_add(1),
+            _ => (),
+        }
+    }
+}
+
+const PUBLISH: u8 = 0x0C;
+const PINGREQ: u8 = 0x16;
+
+/// MsgType field of a packet, following the one or three byte length field
+fn msg_type(packet: &[u8]) -> Option<u8> {
+    match packet.first() {
+        Some(1) => packet.get(3).copied(),
+        Some(_) => packet.get(1).copied(),
+        None => None,
+    }
+}
@@ -0,0 +1,76 @@
+use no_std_net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
+use crate::mqttsn::MqttSnClientError;
+
+type Error = MqttSnClientError;
+
+pub type GwAddr = SocketAddr;
+
+/// Resolve the address of a gateway from the GwAdd field of a GwInfo.
+///
+/// A GwInfo relayed by another client carries the gateway address, encoded as
+/// an IPv4 (6 bytes) or IPv6 (18 bytes) address followed by the port.
+/// A GwInfo sent by the gateway itself leaves it out, in which case the
+/// source address of the datagram is the gateway.
+pub fn gateway_address(gw_add: &[u8], source: SocketAddr) -> Result<GwAddr, Error> {
+    match gw_add.len() {
+        0 => Ok(source),
+        6 => {
+            let ip = Ipv4Addr::new(gw_add[0], gw_add[1], gw_add[2], gw_add[3]);
+            let port = u16::from_be_bytes([gw_add[4], gw_add[5]]);
+            Ok(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+        },
+        18 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&gw_add[..16]);
+            let port = u16::from_be_bytes([gw_add[16], gw_add[17]]);
+            Ok(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0)))
+        },
+        _ => Err(Error::ParseError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source() -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 1884))
+    }
+
+    #[test]
+    fn empty_address_is_the_source() {
+        assert_eq!(gateway_address(&[], source()).unwrap(), source());
+    }
+
+    #[test]
+    fn ipv4_address_and_port() {
+        let gw_add = [192, 0, 2, 7, 0x07, 0x5C];
+        assert_eq!(
+            gateway_address(&gw_add, source()).unwrap(),
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 7), 1884))
+        );
+    }
+
+    #[test]
+    fn ipv6_address_and_port() {
+        let mut gw_add = [0u8; 18];
+        gw_add[..2].copy_from_slice(&[0x20, 0x01]);
+        gw_add[2..4].copy_from_slice(&[0x0D, 0xB8]);
+        gw_add[15] = 1;
+        gw_add[16..].copy_from_slice(&1885u16.to_be_bytes());
+        assert_eq!(
+            gateway_address(&gw_add, source()).unwrap(),
+            SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::new(0x2001, 0xDB8, 0, 0, 0, 0, 0, 1), 1885, 0, 0
+            ))
+        );
+    }
+
+    #[test]
+    fn other_lengths_are_rejected() {
+        for len in [1, 4, 5, 7, 16, 17, 19] {
+            let gw_add = [1u8; 19];
+            assert!(matches!(gateway_address(&gw_add[..len], source()), Err(Error::ParseError)), "{}", len);
+        }
+    }
+}
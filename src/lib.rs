@@ -6,6 +6,9 @@
 pub mod mqttsn;
 pub mod socket;
 pub mod topics;
+pub mod gateway;
+pub mod events;
+pub mod session;
 // pub(crate) mod ackmap;
 
 #[cfg(not(feature = "no_std"))]
@@ -14,3 +17,5 @@ pub mod dtls_std;
 #[cfg(feature = "no_std")]
 pub mod dtls_nrf;
 
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
@@ -1,5 +1,6 @@
 use mqttsn_client::mqttsn::{MqttSnClient, MqttMessage};
-use mqttsn_client::dtls_std::DtlsSocket;
+use mqttsn_client::dtls_std::{DtlsSocket, DtlsClient};
+use mqttsn_client::socket::Connect;
 use tokio::time::{sleep, Duration};
 use log::*;
 use embassy_sync::pubsub::PubSubChannel;
@@ -13,7 +14,10 @@ static MQTT_SEND: PubSubChannel::<CriticalSectionRawMutex, MqttMessage, 10, 1, 2
 async fn main() {
     env_logger::init();
     let socket = DtlsSocket::new().await.unwrap();
-    let session = socket.connect(
+    // A DtlsClient rather than a bare session, so `run` can handshake again
+    // if the link fails
+    let mut session = DtlsClient::new(socket);
+    session.connect(
         "illithid.duckdns.org:3443".to_socket_addrs().unwrap().next().unwrap()
     ).await.unwrap();
     info!("DTLS connected");
@@ -27,7 +31,7 @@ async fn main() {
     mqtt_client.connect(120).await.unwrap();
     info!("MQTT-SN connected");
 
-    mqtt_client.subscribe("test/recv".into()).await.unwrap();
+    mqtt_client.subscribe("test/recv", 0).await.unwrap();
     debug!("subscribed");
 
     let mut mqtt_subscriber = MQTT_RECV.dyn_subscriber().unwrap();
@@ -43,10 +47,10 @@ async fn main() {
         },
         async {
             sleep(Duration::from_secs(12)).await;
-            let msg = MqttMessage::new("test/send", "detterenpayload", Some(2)).unwrap();
+            let msg = MqttMessage::new("test/send", "detterenpayload", Some(2), false).unwrap();
             mqtt_publisher.publish_immediate(msg);
             sleep(Duration::from_secs(12)).await;
-            let msg = MqttMessage::new("test/recv", "detterenpayload2", None).unwrap();
+            let msg = MqttMessage::new("test/recv", "detterenpayload2", None, false).unwrap();
             mqtt_publisher.publish_immediate(msg);
         },
         mqtt_client.run(10)
@@ -1,11 +1,21 @@
 use heapless::String;
-use crate::socket::{SendBytes, ReceiveBytes, SocketError};
+use crate::socket::{
+    SendBytes, ReceiveBytes, Connect as ConnectTo, Reconnect, GatewayResolver, SocketError
+};
 use mqtt_sn::defs::*;
 use byte::{TryRead, TryWrite};
 use embassy_sync::pubsub::subscriber::DynSubscriber;
 use embassy_sync::pubsub::publisher::DynPublisher;
-use embassy_time::{with_timeout, Duration, TimeoutError};
-use crate::topics::Topics;
+use embassy_time::{with_timeout, Duration, Instant, TimeoutError, Timer};
+use crate::topics::{
+    Topics, validate_filter, validate_topic_len, topic_matches, short_topic_id, short_topic_name
+};
+use crate::events::{Event, EventLog, Metrics};
+use crate::session::{SessionState, TopicEntry};
+use crate::gateway::{gateway_address, GwAddr};
+use no_std_net::SocketAddr;
+use futures_util::stream::{unfold, Stream};
+use core::sync::atomic::{AtomicBool, Ordering};
 
 #[cfg(feature = "std")]
 use log::*;
@@ -13,12 +23,14 @@ use log::*;
 #[cfg(feature = "no_std")]
 use defmt::*;
 
-const T_RETRY: u8 = 10;
+const T_RETRY: Duration = Duration::from_secs(10);
 const N_RETRY: u8 = 10;
+/// MsgType of the forwarder encapsulation frame
+const ENCAPSULATED: u8 = 0xFE;
 
 type Error = MqttSnClientError;
 
-#[derive(Hash, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
 #[repr(u8)]
 pub enum TopicIdType {
     Id,
@@ -26,12 +38,206 @@ pub enum TopicIdType {
     Short
 }
 
+/// Handler for inbound messages, see `on_message`. Closures need an
+/// allocator, so without std it is a plain function.
+#[cfg(feature = "std")]
+type MessageHandler = Box<dyn FnMut(&MqttMessage)>;
+#[cfg(not(feature = "std"))]
+type MessageHandler = fn(&MqttMessage);
+
+/// Handler for a session the gateway ended, see `on_disconnect`
+#[cfg(feature = "std")]
+type DisconnectHandler = Box<dyn FnMut(DisconnectReason)>;
+#[cfg(not(feature = "std"))]
+type DisconnectHandler = fn(DisconnectReason);
+
+/// Handler for exhausted retries, see `on_ack_exhausted`
+#[cfg(feature = "std")]
+type AckExhaustedHandler = Box<dyn FnMut(AckKind)>;
+#[cfg(not(feature = "std"))]
+type AckExhaustedHandler = fn(AckKind);
+
+/// Handler run while waiting, see `on_tick`
+#[cfg(feature = "std")]
+type TickHandler = Box<dyn FnMut()>;
+#[cfg(not(feature = "std"))]
+type TickHandler = fn();
+
+/// Granted QoS and topic id of each topic passed to `subscribe_all`
+pub type Granted = heapless::Vec<(u8, u16), 16>;
+
 pub enum AckResult {
     Success,
     TopicId(u16),
+    /// QoS granted and topic id assigned by a SUBACK
+    Granted(u8, u16),
+    /// The expected response arrived, but with a non-accepted return code
+    Rejected(ReturnCode),
+    /// An intermediate request from the gateway, answer it and keep waiting
+    Reply(Message),
     None
 }
 
+/// How inbound messages are handed to the application when its channel is full.
+///
+/// `Overwrite` evicts the oldest queued message, so a QoS > 0 message that was
+/// already acknowledged to the gateway can be lost. `Block` waits for the
+/// application to make room, and `DropNew` discards the incoming message
+/// without acknowledging it, leaving redelivery of QoS > 0 to the gateway.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "no_std", derive(Format))]
+pub enum DeliveryStrategy {
+    Overwrite,
+    Block,
+    DropNew,
+}
+
+/// What `publish` does when the gateway can't honor the requested QoS
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "no_std", derive(Format))]
+pub enum QosPolicy {
+    /// Publish at the highest QoS the gateway supports
+    Downgrade,
+    /// Fail with `QoSNotSupported`
+    Fail,
+}
+
+/// Pacing of retries after a failure: reconnects and retransmissions to a
+/// congested gateway wait `initial`, then `multiplier` times longer after
+/// each further failure, up to `max`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BackoffPolicy {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: u32,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(32),
+            multiplier: 2,
+        }
+    }
+}
+
+impl BackoffPolicy {
+    /// The delay following `delay`
+    fn next(&self, delay: Duration) -> Duration {
+        (delay * self.multiplier).min(self.max)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "no_std", derive(Format))]
+pub enum ConnectionState {
+    Disconnected,
+    Connected,
+    Asleep,
+    Awake,
+}
+
+/// Why the gateway ended the session
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "no_std", derive(Format))]
+pub enum DisconnectReason {
+    /// Plain DISCONNECT, the session is gone
+    Disconnected,
+    /// The gateway stopped advertising, see `set_advertise_slack`
+    GatewayLost,
+    /// DISCONNECT with a duration, the client is directed to sleep this long
+    Sleep(u16),
+}
+
+/// Sees every packet received from the gateway, before the client acts on it
+pub trait MessageObserver {
+    fn on_received(&mut self, msg: &Message);
+}
+
+/// The request that `send_ack` was waiting on
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "no_std", derive(Format))]
+pub enum AckKind {
+    Connect,
+    Register,
+    Publish,
+    Subscribe,
+    Ping,
+    WillUpdate,
+    Disconnect,
+    Other,
+}
+
+impl From<&Message> for AckKind {
+    fn from(msg: &Message) -> Self {
+        match msg {
+            Message::Connect(_) => AckKind::Connect,
+            Message::Register(_) => AckKind::Register,
+            Message::Publish(_) | Message::PubRel(_) => AckKind::Publish,
+            Message::Subscribe(_) => AckKind::Subscribe,
+            Message::PingReq(_) => AckKind::Ping,
+            Message::WillTopicUpd(_) | Message::WillMsgUpd(_) => AckKind::WillUpdate,
+            Message::Disconnect(_) => AckKind::Disconnect,
+            _ => AckKind::Other,
+        }
+    }
+}
+
+/// Where the time of a `publish_detailed` call went
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "no_std", derive(Format))]
+pub struct PublishTiming {
+    /// Topic lookup and registration round-trip, if one was needed
+    pub register_ms: u64,
+    /// Sending and waiting for the acknowledgement, including retries
+    pub ack_wait_ms: u64,
+    /// Retransmissions of the PUBLISH
+    pub retries: u8,
+}
+
+/// A resolved topic for repeated publishing with `publish_to`
+#[derive(Debug, Clone, Copy)]
+pub struct TopicHandle {
+    topic_type: TopicIdType,
+    topic_id: u16,
+    generation: u16,
+}
+
+impl TopicHandle {
+    pub fn topic_id(&self) -> u16 {
+        self.topic_id
+    }
+}
+
+/// Session parameters for `connect_opts`
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectOptions {
+    /// Keep-alive in seconds
+    pub duration: u16,
+    /// Start a new session instead of resuming the previous one
+    pub clean_session: bool,
+}
+
+/// A QoS 1 or 2 publish sent without waiting for its acknowledgement
+struct InFlight {
+    msg_id: u16,
+    /// PUBLISH, or PUBREL once a QoS 2 publish was received
+    packet: Message,
+    sent: Instant,
+    attempts: u8,
+}
+
+/// Round-trip statistics from a series of pings
+#[derive(Debug, Clone, Copy)]
+pub struct LinkStats {
+    pub sent: u8,
+    pub lost: u8,
+    pub min_rtt: Option<Duration>,
+    pub max_rtt: Option<Duration>,
+    pub avg_rtt: Option<Duration>,
+}
+
 impl TryFrom<u8> for TopicIdType {
     type Error = MqttSnClientError;
     fn try_from(i: u8) -> Result<Self, Error> {
@@ -44,14 +250,72 @@ impl TryFrom<u8> for TopicIdType {
     }
 }
 
-pub struct MqttSnClient<S> {
+/// MQTT-SN client over the transport `S`. The packet buffer `B` is owned by
+/// default, but can be borrowed (`&mut [u8]`) so that clients that never run
+/// concurrently can share one buffer. Its size is up to the application,
+/// e.g. `[u8; 256]` on small targets, a packet that doesn't fit fails with
+/// `BufferOverflow`.
+pub struct MqttSnClient<S, B = [u8; 1024]> {
     client_id: ClientId,
     msg_id: MsgId,
     socket: S,
+    /// Ids of topics we publish on, registered by us
     topics: Topics,
+    /// Ids of topics we receive on, assigned through SUBACK or gateway REGISTER
+    sub_topics: Topics,
+    /// Messages queued by the application to be published
     rx: DynSubscriber<'static, MqttMessage>,
+    /// Received messages for the application, which can drain them without
+    /// blocking through its own subscriber's `try_next_message_pure`
     tx: DynPublisher<'static, MqttMessage>,
-    buffer: [u8; 1024],
+    delivery: Option<DeliveryStrategy>,
+    manual_ack: bool,
+    /// Inbound QoS 2 messages waiting for PUBREL
+    pending_qos2: heapless::Vec<MqttMessage, 4>,
+    /// (topic id, msg_id) of recently acknowledged inbound messages
+    delivered: heapless::Deque<(u16, u16), 16>,
+    keep_alive: u16,
+    /// Last transmission or PINGRESP, the keep-alive ping is scheduled from it
+    last_activity: Instant,
+    /// A PINGREQ of ours is waiting for its PINGRESP
+    ping_outstanding: bool,
+    gateway: Option<SocketAddr>,
+    /// Failover candidates for `failover`, in order of preference
+    gateways: heapless::Vec<SocketAddr, 4>,
+    /// Gateway id, announced interval and arrival of the last ADVERTISE
+    last_advertise: Option<(u8, u16, Instant)>,
+    /// Advertise intervals without an ADVERTISE before the gateway is
+    /// considered gone, 0 to never
+    advertise_slack: u8,
+    clean_session: bool,
+    state: ConnectionState,
+    on_disconnect: Option<DisconnectHandler>,
+    on_ack_exhausted: Option<AckExhaustedHandler>,
+    on_tick: Option<(Duration, TickHandler)>,
+    observer: Option<&'static mut dyn MessageObserver>,
+    /// Set from another task to have `run` shut down
+    shutdown: Option<&'static AtomicBool>,
+    /// Handlers for inbound messages by subscription filter
+    handlers: heapless::FnvIndexMap<String<256>, MessageHandler, 8>,
+    qos_policy: QosPolicy,
+    backoff: BackoffPolicy,
+    max_qos: Option<u8>,
+    /// Subscribed topics with the requested QoS
+    subscriptions: heapless::Vec<(String<256>, u8), 16>,
+    events: EventLog<16>,
+    metrics: Metrics,
+    last_error: Option<(Instant, Error)>,
+    /// Wait for an acknowledgement before retransmitting
+    t_retry: Duration,
+    /// Transmissions before giving up
+    n_retry: u8,
+    retries: u8,
+    /// Unacknowledged publishes allowed at once, 1 waits for each ack
+    inflight_limit: u8,
+    inflight: heapless::Vec<InFlight, 8>,
+    /// Forwarder encapsulation header put in front of every packet sent
+    forwarder: Option<heapless::Vec<u8, 19>>,
+    buffer: B,
 }
 
 impl<S> MqttSnClient<S>
@@ -64,254 +328,2152 @@ where
         tx: DynPublisher<'static, MqttMessage>,
         socket: S
     ) -> Result<MqttSnClient<S>, Error> {
+        MqttSnClientBuilder::new(client_id, socket)
+            .rx(rx)
+            .tx(tx)
+            .build()
+    }
+
+    pub fn builder(client_id: &str, socket: S) -> MqttSnClientBuilder<'_, S> {
+        MqttSnClientBuilder::new(client_id, socket)
+    }
+
+    /// Recreate a client from an exported session. Connecting again without
+    /// a clean session resumes it without registering or subscribing.
+    pub fn restore_session(
+        state: SessionState,
+        client_id: &str,
+        rx: DynSubscriber<'static, MqttMessage>,
+        tx: DynPublisher<'static, MqttMessage>,
+        socket: S
+    ) -> Result<MqttSnClient<S>, Error> {
+        let mut client = Self::new(client_id, rx, tx, socket)?;
+        client.msg_id = MsgId { last_id: state.msg_id };
+        client.keep_alive = state.keep_alive;
+        restore_topics(&mut client.topics, &state.topics)?;
+        restore_topics(&mut client.sub_topics, &state.sub_topics)?;
+        for (topic, qos) in state.subscriptions {
+            validate_topic_len(&topic).map_err(|_| Error::InvalidSession)?;
+            validate_filter(&topic).map_err(|_| Error::InvalidSession)?;
+            if !client.subscriptions.iter().any(|(s, _)| *s == topic) {
+                client.subscriptions.push((topic, qos)).map_err(|_| Error::InvalidSession)?;
+            }
+        }
+        Ok(client)
+    }
+}
+
+/// Configures and creates a `MqttSnClient`. The channels are required, the
+/// other settings default to what `MqttSnClient::new` uses.
+pub struct MqttSnClientBuilder<'a, S> {
+    client_id: &'a str,
+    socket: S,
+    rx: Option<DynSubscriber<'static, MqttMessage>>,
+    tx: Option<DynPublisher<'static, MqttMessage>>,
+    keep_alive: u16,
+    clean_session: bool,
+    t_retry: Duration,
+    n_retry: u8,
+    delivery: Option<DeliveryStrategy>,
+}
+
+impl<'a, S> MqttSnClientBuilder<'a, S>
+where
+    S: SendBytes + ReceiveBytes
+{
+    pub fn new(client_id: &'a str, socket: S) -> Self {
+        Self {
+            client_id, socket,
+            rx: None,
+            tx: None,
+            keep_alive: 0,
+            clean_session: false,
+            t_retry: T_RETRY,
+            n_retry: N_RETRY,
+            delivery: None,
+        }
+    }
+
+    /// Messages to publish
+    pub fn rx(mut self, rx: DynSubscriber<'static, MqttMessage>) -> Self {
+        self.rx = Some(rx);
+        self
+    }
+
+    /// Received messages
+    pub fn tx(mut self, tx: DynPublisher<'static, MqttMessage>) -> Self {
+        self.tx = Some(tx);
+        self
+    }
+
+    /// Keep-alive in seconds for the ping scheduler until the first connect
+    pub fn keep_alive(mut self, keep_alive: u16) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn clean_session(mut self, clean_session: bool) -> Self {
+        self.clean_session = clean_session;
+        self
+    }
+
+    /// See `MqttSnClient::with_retry`
+    pub fn retry(mut self, t_retry: Duration, n_retry: u8) -> Self {
+        self.t_retry = t_retry;
+        self.n_retry = n_retry;
+        self
+    }
+
+    /// See `MqttSnClient::set_delivery_strategy`
+    pub fn delivery_strategy(mut self, strategy: DeliveryStrategy) -> Self {
+        self.delivery = Some(strategy);
+        self
+    }
+
+    pub fn build(self) -> Result<MqttSnClient<S>, Error> {
+        let rx = self.rx.ok_or(Error::MissingChannel)?;
+        let tx = self.tx.ok_or(Error::MissingChannel)?;
+        let mut client = MqttSnClient::with_buffer(
+            self.client_id, rx, tx, self.socket, [0u8; 1024]
+        )?
+            .with_keep_alive(self.keep_alive)
+            .with_retry(self.t_retry, self.n_retry);
+        client.set_clean_session(self.clean_session);
+        if let Some(strategy) = self.delivery {
+            client.set_delivery_strategy(strategy);
+        }
+        Ok(client)
+    }
+}
+
+/// Refill a topic table, rejecting ids claimed twice within an id space
+fn restore_topics(topics: &mut Topics, entries: &[TopicEntry]) -> Result<(), Error> {
+    for (topic, topic_type, id) in entries {
+        validate_topic_len(topic).map_err(|_| Error::InvalidSession)?;
+        if topics.get_by_id(*topic_type, *id).is_ok() || topics.get_by_topic(topic).is_some() {
+            return Err(Error::InvalidSession);
+        }
+        topics.insert(topic.clone(), *topic_type, *id)?;
+    }
+    Ok(())
+}
+
+impl<S, B> MqttSnClient<S, B>
+where
+    S: SendBytes + ReceiveBytes,
+    B: AsMut<[u8]>
+{
+    /// Create a client using `buffer` for encoding and decoding packets.
+    /// It must fit the largest packet sent or received.
+    pub fn with_buffer(
+        client_id: &str,
+        rx: DynSubscriber<'static, MqttMessage>,
+        tx: DynPublisher<'static, MqttMessage>,
+        socket: S,
+        buffer: B
+    ) -> Result<MqttSnClient<S, B>, Error> {
         Ok(MqttSnClient {
-            client_id: client_id.into(),
+            client_id: client_id_from(client_id)?,
             msg_id: MsgId {last_id: 0},
             topics: Topics::new(),
+            sub_topics: Topics::new(),
             socket, rx, tx,
-            buffer: [0u8; 1024]
+            delivery: None,
+            manual_ack: false,
+            pending_qos2: heapless::Vec::new(),
+            delivered: heapless::Deque::new(),
+            keep_alive: 0,
+            last_activity: Instant::now(),
+            ping_outstanding: false,
+            gateway: None,
+            gateways: heapless::Vec::new(),
+            last_advertise: None,
+            advertise_slack: 2,
+            clean_session: false,
+            state: ConnectionState::Disconnected,
+            on_disconnect: None,
+            on_ack_exhausted: None,
+            on_tick: None,
+            observer: None,
+            shutdown: None,
+            handlers: heapless::FnvIndexMap::new(),
+            qos_policy: QosPolicy::Fail,
+            backoff: BackoffPolicy::default(),
+            max_qos: None,
+            subscriptions: heapless::Vec::new(),
+            events: EventLog::new(),
+            metrics: Metrics::default(),
+            last_error: None,
+            t_retry: T_RETRY,
+            n_retry: N_RETRY,
+            retries: 0,
+            inflight_limit: 1,
+            inflight: heapless::Vec::new(),
+            forwarder: None,
+            buffer
         })
     }
 
-    pub async fn run(
-        &mut self,
-        sleep: u16,
-    ) {
-        loop {
-            match with_timeout(
-                Duration::from_secs(sleep.into()),
-                self.rx.next_message_pure()
-            ).await {
-                Ok(msg) => {
-                    // Handle message received from the user (via DynSubscriber)
-                    self.connect(sleep).await.unwrap();
-                    self.publish(msg).await.unwrap();
-                    // Publish aditional msg if queued
-                    while let Some(msg) = self.rx.try_next_message_pure() {
-                        self.publish(msg).await.unwrap();
-                    }
-                    self.disconnect(Some(sleep)).await.unwrap();
-                },
-                _ => {
-                    self.ping().await.unwrap();
-                }
-            }
+    /// Declare a topic id agreed with the gateway beforehand. Publishing and
+    /// subscribing then use the id without registering, and it survives
+    /// clean sessions.
+    pub fn register_predefined(&mut self, topic: &str, id: u16) -> Result<(), Error> {
+        let topic = String::try_from(topic).map_err(|_| Error::TopicFailedInsert)?;
+        self.topics.insert(topic.clone(), TopicIdType::PreDef, id)?;
+        self.sub_topics.insert(topic, TopicIdType::PreDef, id)
+    }
+
+    /// Snapshot of the session for a warm restart with `restore_session`
+    pub fn export_session(&self) -> SessionState {
+        SessionState {
+            msg_id: self.msg_id.last_id,
+            keep_alive: self.keep_alive,
+            topics: export_topics(&self.topics),
+            sub_topics: export_topics(&self.sub_topics),
+            subscriptions: self.subscriptions.clone(),
         }
     }
 
-    pub async fn receive(&mut self) -> Result<Option<Message>, Error> {
-        loop {
-            match Message::try_read(
-                with_timeout(
-                    Duration::from_secs(T_RETRY.into()),
-                    self.socket.recv(&mut self.buffer)).await??, ()
-                ) {
-                Ok((Message::Publish(msg), _)) => self.recieve_publish(msg).await?,
-                Ok((msg, _)) => return Ok(Some(msg)),
-                _ => return Err(MqttSnClientError::AckError)
-            }
+    /// Wrap packets in the forwarder encapsulation frame (length, Ctrl,
+    /// Wireless Node Id), for talking to a gateway on behalf of the node
+    /// `node_id`. Received frames are unwrapped, plain packets pass through.
+    /// The node id is 1 to 16 bytes.
+    pub fn with_forwarder(mut self, node_id: &[u8]) -> Result<Self, Error> {
+        if node_id.is_empty() || node_id.len() > 16 {
+            return Err(Error::InvalidNodeId);
         }
+        let mut header = heapless::Vec::new();
+        // Ctrl 0: radius is only meaningful towards the forwarder
+        let _ = header.extend_from_slice(&[3 + node_id.len() as u8, ENCAPSULATED, 0]);
+        let _ = header.extend_from_slice(node_id);
+        self.forwarder = Some(header);
+        Ok(self)
     }
 
-    async fn recieve_publish(&mut self, msg: Publish) -> Result<(), Error> {
-        let msg = MqttMessage::from_publish(msg, &self.topics)?;
-        if msg.qos > Some(0) {
-            if let Some(ack) = msg.get_ack() {
-                self.send(Message::PubAck(ack)).await?;
-            }
+    /// Start the msg_id sequence at `first` instead of 1, e.g. to continue a
+    /// persistent session. 0 is reserved and rejected.
+    pub fn with_msg_id(mut self, first: u16) -> Result<Self, Error> {
+        if first == 0 {
+            return Err(Error::InvalidMsgId);
         }
-        self.tx.publish_immediate(msg);
-        Ok(())
+        self.msg_id = MsgId { last_id: first - 1 };
+        Ok(self)
     }
 
-    pub async fn send(&mut self, msg: Message) -> Result<(), Error> {
-        let len = msg.try_write(&mut self.buffer, ())?;
-        self.socket.send(&self.buffer[..len]).await?;
-        Ok(())
+    /// Keep-alive in seconds for the ping scheduler. Each connect replaces
+    /// it with the duration it negotiated.
+    pub fn with_keep_alive(mut self, keep_alive: u16) -> Self {
+        self.keep_alive = keep_alive;
+        self
     }
 
-    pub async fn send_ack<F>(
-        &mut self, packet: Message, ack_handler: F
-    ) -> Result<AckResult, Error>
+    /// Wait `t_retry` for each acknowledgement and give up after `n_retry`
+    /// transmissions. Defaults to 10 seconds and 10. Every packet is sent at
+    /// least once, an `n_retry` of 0 counts as 1.
+    pub fn with_retry(mut self, t_retry: Duration, n_retry: u8) -> Self {
+        self.t_retry = t_retry;
+        self.n_retry = n_retry.max(1);
+        self
+    }
+
+    /// Override the inbound delivery strategy. By default QoS 0 messages
+    /// use `Overwrite` and QoS > 0 messages use `Block`.
+    pub fn set_delivery_strategy(&mut self, strategy: DeliveryStrategy) {
+        self.delivery = Some(strategy);
+    }
+
+    /// In manual-ack mode inbound QoS 1/2 messages are delivered without
+    /// being acknowledged, the application calls `ack` once it has processed
+    /// them, or `nack` to have the gateway redeliver. For QoS 2 `ack` answers
+    /// with PUBREC, the PUBREL that follows is completed by the client.
+    pub fn set_manual_ack(&mut self, manual_ack: bool) {
+        self.manual_ack = manual_ack;
+    }
+
+    /// Let up to `limit` (at most 8) QoS 1 and 2 publishes await their
+    /// acknowledgement at once. `publish` then returns once the PUBLISH is
+    /// sent, blocking only while the window is full, and acknowledgements
+    /// are collected by later calls or `flush`. Publishes leave in the order
+    /// they were made, but a retransmitted one may arrive after those that
+    /// followed it, so only the default of 1 keeps a topic strictly ordered.
+    pub fn set_inflight_limit(&mut self, limit: u8) {
+        self.inflight_limit = limit.clamp(1, 8);
+    }
+
+    /// Acknowledge an inbound message in manual-ack mode
+    pub async fn ack(&mut self, msg: &MqttMessage) -> Result<(), Error> {
+        match (msg.get_ack(), msg.qos) {
+            (Some(ack), Some(1)) => self.send(Message::PubAck(ack)).await,
+            (Some(ack), Some(2)) => self.send(Message::PubRec(PubRec { msg_id: ack.msg_id })).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Refuse an inbound message in manual-ack mode. MQTT-SN has no NACK, so
+    /// this withholds the PUBACK and relies on the gateway retransmitting the
+    /// message once its retry timer expires. The message is then delivered
+    /// again, possibly with DUP set, so the application must tolerate
+    /// duplicates. A gateway that gives up after its retries drops it.
+    pub fn nack(&mut self, msg_id: u16) {
+        debug!("withholding ack for msg_id {}", msg_id);
+    }
+
+    /// Choose whether publishes above the QoS supported by the gateway are
+    /// downgraded or rejected. Defaults to `QosPolicy::Fail`.
+    pub fn set_qos_policy(&mut self, policy: QosPolicy) {
+        self.qos_policy = policy;
+    }
+
+    /// Pacing of reconnect attempts and of retransmissions to a congested
+    /// gateway. Defaults to 1 second, doubling up to 32.
+    pub fn set_backoff(&mut self, policy: BackoffPolicy) {
+        self.backoff = policy;
+    }
+
+    /// Counters of packets, retransmissions and failures since creation
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Recent protocol events, oldest first, for post-mortem debugging
+    pub fn recent_events(&self) -> impl Iterator<Item = Event> + '_ {
+        self.events.iter().cloned()
+    }
+
+    /// Request a clean session on the next connect. Registered topic ids are
+    /// then forgotten, while a persistent session keeps them. Predefined and
+    /// short topics survive either way.
+    pub fn set_clean_session(&mut self, clean_session: bool) {
+        self.clean_session = clean_session;
+    }
+
+    /// Called when the gateway ends the session on its own
+    #[cfg(feature = "std")]
+    pub fn on_disconnect<F>(&mut self, handler: F)
     where
-        F: Fn(Message) -> AckResult
+        F: FnMut(DisconnectReason) + 'static
     {
-        let len = packet.try_write(&mut self.buffer, ())?;
-        
-        for _ in 1..N_RETRY {
-            self.socket.send(&self.buffer[..len]).await?;
+        self.on_disconnect = Some(Box::new(handler));
+    }
 
-            match with_timeout(
-                Duration::from_secs(T_RETRY.into()),
-                async {
-                    loop{
-                        if let Ok(Some(msg)) = self.receive().await {
-                            match ack_handler(msg) {
-                                AckResult::None => (),
-                                result => return result,
-                            }
-                        }
-                    }
-                }).await
-            {
-                Ok(result) => return Ok(result),
-                _ => ()
-            }
+    /// Called when the gateway ends the session on its own
+    #[cfg(not(feature = "std"))]
+    pub fn on_disconnect(&mut self, handler: fn(DisconnectReason)) {
+        self.on_disconnect = Some(handler);
+    }
+
+    /// Called when `send_ack` runs out of retries, just before it returns
+    /// `AckError`. A place to hook deeper recovery such as a transport reset.
+    #[cfg(feature = "std")]
+    pub fn on_ack_exhausted<F>(&mut self, handler: F)
+    where
+        F: FnMut(AckKind) + 'static
+    {
+        self.on_ack_exhausted = Some(Box::new(handler));
+    }
+
+    /// Called when `send_ack` runs out of retries, just before it returns
+    /// `AckError`. A place to hook deeper recovery such as a transport reset.
+    #[cfg(not(feature = "std"))]
+    pub fn on_ack_exhausted(&mut self, handler: fn(AckKind)) {
+        self.on_ack_exhausted = Some(handler);
+    }
+
+    /// Called at least every `interval` while the client waits on the socket,
+    /// and once per transmission attempt in `send_ack`, e.g. to pet a
+    /// watchdog during long ack waits. Keep it short, it runs inline.
+    #[cfg(feature = "std")]
+    pub fn on_tick<F>(&mut self, interval: Duration, handler: F)
+    where
+        F: FnMut() + 'static
+    {
+        self.on_tick = Some((interval, Box::new(handler)));
+    }
+
+    /// Called at least every `interval` while the client waits on the socket,
+    /// and once per transmission attempt in `send_ack`, e.g. to pet a
+    /// watchdog during long ack waits. Keep it short, it runs inline.
+    #[cfg(not(feature = "std"))]
+    pub fn on_tick(&mut self, interval: Duration, handler: fn()) {
+        self.on_tick = Some((interval, handler));
+    }
+
+    /// Change the client id used by the next connect, e.g. after
+    /// provisioning. Only allowed while disconnected, the gateway knows the
+    /// session by the old id until then.
+    pub fn set_client_id(&mut self, id: &str) -> Result<(), Error> {
+        if self.state != ConnectionState::Disconnected {
+            return Err(Error::NotDisconnected);
         }
-        Err(Error::AckError)
+        let mut client_id = ClientId::new();
+        client_id.push_str(id).map_err(|_| Error::MessageTooLarge)?;
+        self.client_id = client_id;
+        Ok(())
     }
 
-    pub async fn ping(&mut self) -> Result<(), Error>{
-        debug!("ping");
-        let packet = Message::PingReq(PingReq {
-            client_id: self.client_id.clone()
-        });
-        let ack_handler = |msg| {
-            match msg {
-                Message::PingResp(_) => AckResult::Success,
-                _ => AckResult::None
-            }
-        };
+    /// Show every decoded inbound packet to `observer` before it is handled,
+    /// e.g. for logging
+    pub fn set_observer(&mut self, observer: &'static mut dyn MessageObserver) {
+        self.observer = Some(observer);
+    }
 
-        self.send_ack(packet, ack_handler).await?;
+    /// Have `run` call `shutdown` and return once `flag` is set. It is checked
+    /// each time `run` wakes up, at the latest after its sleep interval.
+    pub fn set_shutdown_flag(&mut self, flag: &'static AtomicBool) {
+        self.shutdown = Some(flag);
+    }
+
+    /// Call `handler` for each inbound message on topics matching `filter`,
+    /// which may contain wildcards. Handlers run after the message has been
+    /// handed to the `tx` channel, and not at all if the channel dropped it,
+    /// or just before `next_message` returns it. Registering a filter again
+    /// replaces its handler.
+    #[cfg(feature = "std")]
+    pub fn on_message<F>(&mut self, filter: &str, handler: F) -> Result<(), Error>
+    where
+        F: FnMut(&MqttMessage) + 'static
+    {
+        self.insert_handler(filter, Box::new(handler))
+    }
+
+    /// Call `handler` for each inbound message on topics matching `filter`,
+    /// which may contain wildcards. Handlers run after the message has been
+    /// handed to the `tx` channel, and not at all if the channel dropped it,
+    /// or just before `next_message` returns it. Registering a filter again
+    /// replaces its handler.
+    #[cfg(not(feature = "std"))]
+    pub fn on_message(&mut self, filter: &str, handler: fn(&MqttMessage)) -> Result<(), Error> {
+        self.insert_handler(filter, handler)
+    }
+
+    fn insert_handler(&mut self, filter: &str, handler: MessageHandler) -> Result<(), Error> {
+        validate_filter(filter)?;
+        let filter = String::try_from(filter).map_err(|_| Error::InvalidFilter)?;
+        self.handlers.insert(filter, handler).map_err(|_| Error::TopicFailedInsert)?;
         Ok(())
     }
 
-    pub async fn publish(&mut self, msg: MqttMessage) -> Result<(), Error> {
-        debug!("publish");
-        let mut flags = Flags::default();
-        if let Some(qos) = msg.qos {
-            flags.set_qos(qos)
+    /// Topics used for publishing, with their id type and id
+    pub fn topics(&self) -> impl Iterator<Item = (&str, TopicIdType, u16)> {
+        self.topics.iter()
+    }
+
+    /// Topics received on, with the id type and id assigned by the gateway
+    pub fn inbound_topics(&self) -> impl Iterator<Item = (&str, TopicIdType, u16)> {
+        self.sub_topics.iter()
+    }
+
+    /// Whether the client is connected, asleep or disconnected, as far as it
+    /// knows from its own requests and the gateway's DISCONNECTs
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// The most recent error seen by the client, including ones it
+    /// recovered from, such as an ack timeout followed by a successful retry
+    pub fn last_error(&self) -> Option<(Instant, Error)> {
+        self.last_error.clone()
+    }
+
+    /// Gateways `failover` falls back to, in order of preference
+    pub fn set_gateways(&mut self, gateways: heapless::Vec<SocketAddr, 4>) {
+        self.gateways = gateways;
+    }
+
+    /// The gateway the session was last established with through
+    /// `connect_any`, `failover` or `switch_gateway`
+    pub fn active_gateway(&self) -> Option<SocketAddr> {
+        self.gateway
+    }
+
+    /// Time since a gateway last sent ADVERTISE. Gateways advertise at the
+    /// interval they announce, so a much longer silence suggests the gateway
+    /// is gone.
+    pub fn gateway_last_seen(&self) -> Option<Duration> {
+        self.last_advertise.map(|(_, _, seen)| seen.elapsed())
+    }
+
+    /// How many advertised intervals `run` lets pass without an ADVERTISE
+    /// before taking the gateway for gone and reconnecting. Defaults to 2,
+    /// 0 disables the check. It only applies once an ADVERTISE was heard.
+    pub fn set_advertise_slack(&mut self, factor: u8) {
+        self.advertise_slack = factor;
+    }
+
+    fn heard_advertise(&mut self, advertise: &Advertise) {
+        self.last_advertise = Some((advertise.gw_id, advertise.duration, Instant::now()));
+    }
+
+    /// Whether the gateway missed more advertisements than the slack allows
+    fn gateway_lost(&self) -> bool {
+        match self.last_advertise {
+            Some((_, interval, seen)) if self.advertise_slack > 0 => {
+                let allowed = Duration::from_secs(u64::from(interval) * u64::from(self.advertise_slack));
+                seen.elapsed() > allowed
+            },
+            _ => false,
         }
+    }
+
+    fn record_error(&mut self, e: Error) {
+        self.events.record(Event::Error(e.clone()));
+        self.last_error = Some((Instant::now(), e));
+    }
 
-        let topic_id;
-        if let Some((topic_type, id)) = self.topics.get_by_topic(&msg.topic) {
-            topic_id = *id;
-            flags.set_topic_id_type(*topic_type as u8);
-        } else {
-            topic_id = self.register(&msg.topic).await?;
-            self.topics.insert(msg.topic, TopicIdType::Id, topic_id)?;
+    /// Time left until a PINGREQ is due to keep the session alive: 3/4 of the
+    /// keep-alive period after the last transmission, so the ping arrives
+    /// before the gateway gives up. `None` without a keep-alive.
+    fn keep_alive_due(&self) -> Option<Duration> {
+        if self.keep_alive == 0 {
+            return None;
         }
-        let next_msg_id = self.msg_id.next();
+        let period = Duration::from_millis(u64::from(self.keep_alive) * 750);
+        Some(period.checked_sub(self.last_activity.elapsed()).unwrap_or(Duration::from_ticks(0)))
+    }
 
-        let mut data = PublishData::new();
-        data.push_str(&msg.payload)?;
-        let packet = Message::Publish(
-            Publish {flags, topic_id, msg_id: next_msg_id, data}
-        );
-
-        // Get ACK for QoS 1 & 2
-        match msg.qos {
-            Some(qos) if qos > 0 => {
-                let ack_handler = |msg| {
-                    match msg {
-                        Message::PubAck(PubAck {
-                            msg_id, code: ReturnCode::Accepted, ..
-                        }) if msg_id == next_msg_id => AckResult::Success,
-                        _ => AckResult::None
-                    }
-                };
-                self.send_ack(packet, ack_handler).await?;
-            },
-            _ => {
-                self.send(packet.into()).await?;
-            },
+    /// Connect, publish `msg` and anything else queued on `rx`, then go back
+    /// to sleep for `sleep` seconds
+    async fn publish_queued(&mut self, msg: MqttMessage, sleep: u16) -> Result<(), Error> {
+        self.connect(sleep).await?;
+        self.publish_connected(msg, sleep).await?;
+        // Publish aditional msg if queued
+        while let Some(msg) = self.rx.try_next_message_pure() {
+            self.publish_connected(msg, sleep).await?;
         }
-        Ok(())
+        self.flush().await?;
+        self.disconnect(Some(sleep)).await
     }
 
-    async fn register(&mut self, topic: &String<256>) -> Result<u16, Error> {
-        debug!("register");
-        let msg_id = self.msg_id.next();
-        let packet = Message::Register(Register {
-            topic_id: 0,
-            msg_id,
-            topic_name: TopicName::from(&topic)
-        });
-        let ack_handler = |msg| {
-            match msg {
-                Message::RegAck(RegAck {
-                    topic_id, code: ReturnCode::Accepted, ..
-                }) => AckResult::TopicId(topic_id),
-                _ => AckResult::None
-            }
-        };
-        
-        match self.send_ack(packet, ack_handler).await {
-            Ok(AckResult::TopicId(id)) => return Ok(id),
-            _ => Err(Error::AckError)
+    /// Publish whatever is still queued on `rx`, connecting first if needed,
+    /// then end the session with a DISCONNECT
+    pub async fn shutdown(&mut self) -> Result<(), Error> {
+        debug!("shutdown");
+        let duration = self.keep_alive;
+        if self.state != ConnectionState::Connected {
+            self.connect(duration).await?;
         }
+        while let Some(msg) = self.rx.try_next_message_pure() {
+            self.publish_connected(msg, duration).await?;
+        }
+        self.flush().await?;
+        self.disconnect(None).await
     }
 
-    pub async fn connect(&mut self, duration: u16) -> Result<(), Error> {
-        debug!("connect");
-        let packet = Message::Connect(Connect {
-            flags: Flags::default(),
-            duration,
-            client_id: self.client_id.clone()
-        });
-        let ack_handler = |msg| {
+    fn shutdown_requested(&self) -> bool {
+        self.shutdown.map_or(false, |flag| flag.load(Ordering::Relaxed))
+    }
+
+    /// Publish, connecting again if the gateway ended the session
+    async fn publish_connected(
+        &mut self, msg: MqttMessage, duration: u16
+    ) -> Result<Option<u16>, Error> {
+        match self.publish(msg.clone()).await {
+            Err(Error::Disconnected) => {
+                self.reconnect(duration).await?;
+                self.publish(msg).await
+            },
+            result => result,
+        }
+    }
+
+    /// Connect, subscribe to `topics` and hand every received message to
+    /// `on_message`. The gateway is pinged whenever nothing has been sent
+    /// for 3/4 of `keep_alive` seconds, and the session is re-established
+    /// with `reconnect` if the link fails. Messages arriving while waiting for
+    /// a ping response are delivered through the `tx` channel instead.
+    pub async fn run_subscriber<F>(
+        &mut self,
+        topics: &[&str],
+        keep_alive: u16,
+        mut on_message: F
+    ) -> Result<(), Error>
+    where
+        F: FnMut(MqttMessage)
+    {
+        self.start_session(topics, keep_alive).await?;
+        loop {
+            let result = self.next_message().await.map(&mut on_message);
+            if let Err(e) = result {
+                warn!("subscriber session lost: {:?}", e);
+                self.record_error(e);
+                let mut backoff = self.backoff.initial;
+                while let Err(e) = self.reconnect(keep_alive).await {
+                    warn!("failed to re-establish session: {:?}", e);
+                    self.record_error(e);
+                    Timer::after(backoff).await;
+                    backoff = self.backoff.next(backoff);
+                }
+            }
+        }
+    }
+
+    /// Wait for the next application message, answering protocol traffic and
+    /// keeping the session alive with pings in the meantime. The message is
+    /// returned instead of going through the `tx` channel.
+    pub async fn next_message(&mut self) -> Result<MqttMessage, Error> {
+        loop {
+            let timeout = self.keep_alive_due().unwrap_or(self.t_retry);
+            let len = match self.recv_ticking(timeout).await? {
+                Some(len) => len,
+                None => {
+                    if self.keep_alive > 0 {
+                        self.ping().await?;
+                    }
+                    continue;
+                },
+            };
+            let bytes = &self.buffer.as_mut()[..len];
+            self.events.record_packet(bytes, false);
+            let decoded = Message::try_read(bytes, ());
+            self.observe(&decoded);
+            match decoded {
+                Ok((Message::Publish(msg), _)) => {
+                    match self.accept_publish(msg).await {
+                        Ok(Some(msg)) => {
+                            self.dispatch(&msg);
+                            return Ok(msg);
+                        },
+                        Ok(None) => (),
+                        Err(e) => {
+                            warn!("dropping inbound publish: {:?}", e);
+                            self.record_error(e);
+                        },
+                    }
+                },
+                Ok((Message::Register(msg), _)) => self.receive_register(msg).await?,
+                Ok((Message::Disconnect(Disconnect { duration }), _)) => {
+                    if self.gateway_disconnect(duration) {
+                        return Err(Error::Disconnected);
+                    }
+                },
+                Ok((Message::PubRel(PubRel { msg_id }), _)) => {
+                    let released = self.take_pending(msg_id);
+                    self.send(Message::PubComp(PubComp { msg_id })).await?;
+                    if let Some(msg) = released {
+                        self.dispatch(&msg);
+                        return Ok(msg);
+                    }
+                },
+                Ok((Message::Advertise(advertise), _)) => self.heard_advertise(&advertise),
+                Ok((msg, _)) => {
+                    self.inflight_ack(&msg).await?;
+                },
+                Err(e) => {
+                    warn!("skipping undecodable packet");
+                    self.metrics.parse_errors = self.metrics.parse_errors.wrapping_add(1);
+                    self.record_error(Error::CodecError(e));
+                },
+            }
+        }
+    }
+
+    /// Incoming messages as a `Stream`, each item is a `next_message` call
+    pub fn messages(&mut self) -> impl Stream<Item = Result<MqttMessage, Error>> + '_ {
+        unfold(self, |client| async move {
+            let msg = client.next_message().await;
+            Some((msg, client))
+        })
+    }
+
+    async fn start_session(&mut self, topics: &[&str], keep_alive: u16) -> Result<(), Error> {
+        self.connect(keep_alive).await?;
+        self.subscribe_all(topics).await.map_err(|(_, e)| e)?;
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for a datagram and return its length, or `None`
+    /// on timeout. The tick hook runs every tick interval while waiting.
+    async fn recv_ticking(&mut self, timeout: Duration) -> Result<Option<usize>, Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            let wait = match &self.on_tick {
+                Some((interval, _)) => (*interval).min(deadline - now),
+                None => deadline - now,
+            };
+            match with_timeout(wait, self.socket.recv(self.buffer.as_mut())).await {
+                Ok(bytes) => {
+                    let len = bytes?.len();
+                    return Ok(Some(self.unwrap_forwarded(len)));
+                },
+                Err(_) => self.tick(),
+            }
+        }
+    }
+
+    fn observe(&mut self, decoded: &Result<(Message, usize), byte::Error>) {
+        if let (Ok((msg, _)), Some(observer)) = (decoded, self.observer.as_mut()) {
+            observer.on_received(msg);
+        }
+    }
+
+    fn tick(&mut self) {
+        if let Some((_, handler)) = self.on_tick.as_mut() {
+            handler();
+        }
+    }
+
+    /// Receive the next packet that isn't handled internally, waiting up to
+    /// the retry interval. `Ok(None)` on timeout.
+    pub async fn receive(&mut self) -> Result<Option<Message>, Error> {
+        self.receive_timeout(self.t_retry).await
+    }
+
+    /// Wait for the next control packet from the gateway, for building custom
+    /// flows on top of `send`. Application messages, registrations, gateway
+    /// advertisements and acknowledgements of publishes in flight are still
+    /// handled internally and never returned, nor is a PINGRESP unless a
+    /// PINGREQ is waiting for it.
+    pub async fn next_control(&mut self) -> Result<Message, Error> {
+        loop {
+            if let Some(msg) = self.receive().await? {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Like `receive`, giving up after `timeout` with `Ok(None)`. Each packet
+    /// handled internally restarts the wait.
+    pub async fn receive_timeout(&mut self, timeout: Duration) -> Result<Option<Message>, Error> {
+        loop {
+            let len = match self.recv_ticking(timeout).await? {
+                Some(len) => len,
+                None => return Ok(None),
+            };
+            let bytes = &self.buffer.as_mut()[..len];
+            self.events.record_packet(bytes, false);
+            let decoded = Message::try_read(bytes, ());
+            self.observe(&decoded);
+            match decoded {
+                Ok((Message::Publish(msg), _)) => self.recieve_publish(msg).await?,
+                Ok((Message::Register(msg), _)) => self.receive_register(msg).await?,
+                Ok((Message::PubRel(PubRel { msg_id }), _)) => self.receive_release(msg_id).await?,
+                Ok((Message::Advertise(advertise), _)) => self.heard_advertise(&advertise),
+                // Proof the session is alive, only surfaced to a ping waiting for it
+                Ok((Message::PingResp(resp), _)) => {
+                    self.last_activity = Instant::now();
+                    if self.ping_outstanding {
+                        self.ping_outstanding = false;
+                        return Ok(Some(Message::PingResp(resp)));
+                    }
+                },
+                Ok((Message::Disconnect(Disconnect { duration }), _)) => {
+                    if self.gateway_disconnect(duration) {
+                        return Err(Error::Disconnected);
+                    }
+                    return Ok(Some(Message::Disconnect(Disconnect { duration })));
+                },
+                Ok((msg, _)) => {
+                    if !self.inflight_ack(&msg).await? {
+                        return Ok(Some(msg));
+                    }
+                },
+                Err(e) => {
+                    // A single bad datagram shouldn't end a receive or ack wait
+                    warn!("skipping undecodable packet");
+                    self.metrics.parse_errors = self.metrics.parse_errors.wrapping_add(1);
+                    self.record_error(Error::CodecError(e));
+                }
+            }
+        }
+    }
+
+    async fn recieve_publish(&mut self, msg: Publish) -> Result<(), Error> {
+        let dup = msg.flags.dup();
+        let msg = MqttMessage::from_publish(msg, &self.sub_topics)?;
+        if msg.qos == Some(2) && !self.manual_ack {
+            return self.hold_publish(msg).await;
+        }
+        let ack = msg.get_ack().filter(|_| msg.qos > Some(0) && !self.manual_ack);
+        if dup && self.delivered_before(&msg) {
+            debug!("duplicate publish, acknowledging again");
+        } else if !self.deliver(msg).await {
+            debug!("inbound channel full, message dropped");
+            return Ok(());
+        }
+        if let Some(ack) = ack {
+            self.remember_delivered(&ack);
+            self.send(Message::PubAck(ack)).await?;
+        }
+        Ok(())
+    }
+
+    /// Whether a retransmitted PUBLISH was already delivered and acknowledged,
+    /// our PUBACK having been lost
+    fn delivered_before(&self, msg: &MqttMessage) -> bool {
+        match (msg.topic_id, msg.msg_id) {
+            (Some(topic_id), Some(msg_id)) => self.delivered.iter().any(|d| *d == (topic_id, msg_id)),
+            _ => false
+        }
+    }
+
+    fn remember_delivered(&mut self, ack: &PubAck) {
+        if self.delivered.is_full() {
+            self.delivered.pop_front();
+        }
+        let _ = self.delivered.push_back((ack.topic_id, ack.msg_id));
+    }
+
+    /// First half of an inbound QoS 2 delivery: keep the message until the
+    /// gateway releases it and answer with PUBREC. A retransmitted PUBLISH
+    /// only repeats the PUBREC.
+    async fn hold_publish(&mut self, msg: MqttMessage) -> Result<(), Error> {
+        let msg_id = msg.msg_id.ok_or(Error::ParseError)?;
+        if !self.pending_qos2.iter().any(|m| m.msg_id == msg.msg_id) {
+            if self.pending_qos2.push(msg).is_err() {
+                debug!("too many unreleased QoS 2 messages, dropped");
+                return Ok(());
+            }
+        }
+        self.send(Message::PubRec(PubRec { msg_id })).await
+    }
+
+    /// Second half: deliver the held message on PUBREL and answer with
+    /// PUBCOMP. A PUBREL for a message already delivered is answered again
+    /// without redelivering. If the channel drops the message it stays held
+    /// and the PUBCOMP is withheld, so the gateway's next PUBREL retries.
+    async fn receive_release(&mut self, msg_id: u16) -> Result<(), Error> {
+        if let Some(i) = self.pending_qos2.iter().position(|m| m.msg_id == Some(msg_id)) {
+            if !self.deliver(self.pending_qos2[i].clone()).await {
+                debug!("inbound channel full, message held");
+                return Ok(());
+            }
+            self.pending_qos2.swap_remove(i);
+        }
+        self.send(Message::PubComp(PubComp { msg_id })).await
+    }
+
+    fn take_pending(&mut self, msg_id: u16) -> Option<MqttMessage> {
+        let i = self.pending_qos2.iter().position(|m| m.msg_id == Some(msg_id))?;
+        Some(self.pending_qos2.swap_remove(i))
+    }
+
+    /// Store a topic registered by the gateway and acknowledge it. This can
+    /// arrive at any time, e.g. between a SUBSCRIBE and its SUBACK.
+    async fn receive_register(&mut self, msg: Register) -> Result<(), Error> {
+        let stored = String::try_from(msg.topic_name.as_str())
+            .map_err(|_| Error::TopicFailedInsert)
+            .and_then(|topic| self.sub_topics.insert(topic, TopicIdType::Id, msg.topic_id));
+        let code = match stored {
+            Ok(()) => ReturnCode::Accepted,
+            Err(e) => {
+                warn!("failed to store gateway registration: {:?}", e);
+                ReturnCode::Rejected(RejectedReason::NotSupported)
+            }
+        };
+        self.send(Message::RegAck(RegAck {
+            topic_id: msg.topic_id,
+            msg_id: msg.msg_id,
+            code
+        })).await
+    }
+
+    /// Handle a DISCONNECT we didn't ask for. While connected it ends the
+    /// session, or puts the client to sleep if it carries a duration. Once
+    /// we have disconnected ourselves it is just the gateway's confirmation.
+    /// Returns whether the session was ended.
+    fn gateway_disconnect(&mut self, duration: Option<u16>) -> bool {
+        if !matches!(self.state, ConnectionState::Connected | ConnectionState::Awake) {
+            return false;
+        }
+        let reason = match duration {
+            Some(duration) => {
+                self.state = ConnectionState::Asleep;
+                DisconnectReason::Sleep(duration)
+            },
+            None => {
+                self.state = ConnectionState::Disconnected;
+                DisconnectReason::Disconnected
+            },
+        };
+        debug!("gateway disconnected: {:?}", reason);
+        if let Some(handler) = self.on_disconnect.as_mut() {
+            handler(reason);
+        }
+        true
+    }
+
+    /// Decode an inbound publish and acknowledge it. `None` is returned for
+    /// QoS 2 messages, which are held until released by PUBREL, and for
+    /// retransmissions of messages already delivered.
+    async fn accept_publish(&mut self, msg: Publish) -> Result<Option<MqttMessage>, Error> {
+        let dup = msg.flags.dup();
+        let msg = MqttMessage::from_publish(msg, &self.sub_topics)?;
+        if msg.qos == Some(2) && !self.manual_ack {
+            self.hold_publish(msg).await?;
+            return Ok(None);
+        }
+        let duplicate = dup && self.delivered_before(&msg);
+        if let Some(ack) = msg.get_ack().filter(|_| msg.qos > Some(0) && !self.manual_ack) {
+            self.remember_delivered(&ack);
+            self.send(Message::PubAck(ack)).await?;
+        }
+        if duplicate {
+            return Ok(None);
+        }
+        Ok(Some(msg))
+    }
+
+    /// Hand a message to the application, returns false if it was dropped
+    async fn deliver(&mut self, msg: MqttMessage) -> bool {
+        let copy = if self.handlers.is_empty() { None } else { Some(msg.clone()) };
+        let delivered = self.publish_tx(msg).await;
+        if let (true, Some(msg)) = (delivered, copy) {
+            self.dispatch(&msg);
+        }
+        delivered
+    }
+
+    /// Call the handlers whose filter matches the message
+    fn dispatch(&mut self, msg: &MqttMessage) {
+        for (filter, handler) in self.handlers.iter_mut() {
+            if topic_matches(filter, &msg.topic) {
+                handler(msg);
+            }
+        }
+    }
+
+    async fn publish_tx(&self, msg: MqttMessage) -> bool {
+        let strategy = match (self.delivery, msg.qos) {
+            (Some(strategy), _) => strategy,
+            (None, Some(qos)) if qos > 0 => DeliveryStrategy::Block,
+            _ => DeliveryStrategy::Overwrite,
+        };
+        match strategy {
+            DeliveryStrategy::Overwrite => {
+                self.tx.publish_immediate(msg);
+                true
+            },
+            DeliveryStrategy::Block => {
+                self.tx.publish(msg).await;
+                true
+            },
+            DeliveryStrategy::DropNew => self.tx.try_publish(msg).is_ok(),
+        }
+    }
+
+    /// Encode `packet` into the buffer, behind the forwarder header if any,
+    /// and return the length of the whole frame
+    fn encode(&mut self, packet: Message) -> Result<usize, Error> {
+        let buffer = self.buffer.as_mut();
+        let start = match &self.forwarder {
+            Some(header) => {
+                buffer.get_mut(..header.len())
+                    .ok_or(Error::BufferOverflow)?
+                    .copy_from_slice(header);
+                header.len()
+            },
+            None => 0,
+        };
+        let len = packet.try_write(&mut buffer[start..], ()).map_err(|e| match e {
+            byte::Error::Incomplete => Error::BufferOverflow,
+            e => Error::CodecError(e),
+        })?;
+        Ok(start + len)
+    }
+
+    /// Strip the forwarder header from the `len` bytes received into the
+    /// buffer, returning the length of the packet left at its start
+    fn unwrap_forwarded(&mut self, len: usize) -> usize {
+        if self.forwarder.is_none() {
+            return len;
+        }
+        let buffer = self.buffer.as_mut();
+        match buffer[..len] {
+            [header_len, ENCAPSULATED, ..] if usize::from(header_len) < len => {
+                let header_len = usize::from(header_len);
+                buffer.copy_within(header_len..len, 0);
+                len - header_len
+            },
+            _ => len,
+        }
+    }
+
+    /// Send the frame encoded into the first `len` bytes of the buffer
+    async fn send_encoded(&mut self, len: usize) -> Result<(), Error> {
+        self.socket.send(&self.buffer.as_mut()[..len]).await?;
+        self.last_activity = Instant::now();
+        let start = self.forwarder.as_ref().map_or(0, |header| header.len());
+        self.events.record_packet(&self.buffer.as_mut()[start..len], true);
+        self.metrics.record_sent(&self.buffer.as_mut()[start..len]);
+        Ok(())
+    }
+
+    pub async fn send(&mut self, msg: Message) -> Result<(), Error> {
+        // Let the PINGRESP through `receive`, also for pings sent by the user
+        if let Message::PingReq(_) = msg {
+            self.ping_outstanding = true;
+        }
+        let len = self.encode(msg)?;
+        self.send_encoded(len).await
+    }
+
+    pub async fn send_ack<F>(
+        &mut self, mut packet: Message, ack_handler: F
+    ) -> Result<AckResult, Error>
+    where
+        F: Fn(Message) -> AckResult
+    {
+        let kind = AckKind::from(&packet);
+        // Waited before retransmitting to a congested gateway, growing each time
+        let mut backoff = self.backoff.initial;
+        let mut error = Error::AckError;
+        
+        for attempt in 1..=self.n_retry {
+            if attempt > 1 {
+                self.events.record(Event::Retry(attempt));
+                self.metrics.retransmissions = self.metrics.retransmissions.wrapping_add(1);
+                set_dup(&mut packet);
+            }
+            self.retries = attempt - 1;
+            self.tick();
+            // The ack wait below receives into the same buffer, so encode the
+            // packet for every attempt instead of resending the buffer
+            let len = self.encode(packet.clone())?;
+            self.send_encoded(len).await?;
+
+            match with_timeout(
+                self.t_retry,
+                async {
+                    loop{
+                        match self.receive().await {
+                            Ok(Some(msg)) if is_congestion(&packet, &msg) => {
+                                return Err(Error::Congestion);
+                            },
+                            Ok(Some(msg)) => match ack_handler(msg) {
+                                AckResult::None => (),
+                                AckResult::Reply(reply) => {
+                                    if let Err(e) = self.send(reply).await {
+                                        warn!("failed to answer gateway request: {:?}", e);
+                                    }
+                                },
+                                result => return Ok(result),
+                            },
+                            // No point in retrying once the session or the
+                            // transport is gone, `run` recovers from the latter
+                            Err(e @ (Error::Disconnected | Error::SocketError | Error::HandshakeFailed)) => {
+                                return Err(e);
+                            },
+                            _ => (),
+                        }
+                    }
+                }).await
+            {
+                Ok(Err(Error::Congestion)) => {
+                    warn!("gateway congested, retrying in {}s", backoff.as_secs());
+                    error = Error::Congestion;
+                    self.last_error = Some((Instant::now(), Error::Congestion));
+                    Timer::after(backoff).await;
+                    backoff = self.backoff.next(backoff);
+                },
+                Ok(result) => {
+                    if result.is_ok() {
+                        self.metrics.acks_received = self.metrics.acks_received.wrapping_add(1);
+                    }
+                    return result;
+                },
+                _ => {
+                    error = Error::AckError;
+                    self.metrics.timeouts = self.metrics.timeouts.wrapping_add(1);
+                    self.events.record(Event::AckTimeout);
+                    self.last_error = Some((Instant::now(), Error::AckError));
+                }
+            }
+        }
+        self.record_error(error.clone());
+        if let Some(handler) = self.on_ack_exhausted.as_mut() {
+            handler(kind);
+        }
+        Err(error)
+    }
+
+    /// Broadcast SEARCHGW and wait up to `timeout_secs` for a gateway to
+    /// answer, returning its id and address. An ADVERTISE heard in the
+    /// meantime is taken as an answer too.
+    pub async fn search_gateway(
+        &mut self, radius: u8, timeout_secs: u16
+    ) -> Result<(u8, GwAddr), Error> {
+        debug!("search gateway");
+        self.send(Message::SearchGw(SearchGw { radius })).await?;
+        with_timeout(
+            Duration::from_secs(timeout_secs.into()),
+            async {
+                loop {
+                    let (bytes, source) = self.socket.recv_from(self.buffer.as_mut()).await?;
+                    let len = bytes.len();
+                    let len = self.unwrap_forwarded(len);
+                    let bytes = &self.buffer.as_mut()[..len];
+                    self.events.record_packet(bytes, false);
+                    match Message::try_read(bytes, ()) {
+                        Ok((Message::GwInfo(info), _)) => {
+                            match gateway_address(&info.gw_add, source) {
+                                Ok(addr) => return Ok((info.gw_id, addr)),
+                                Err(_) => warn!("skipping GwInfo with malformed address"),
+                            }
+                        },
+                        Ok((Message::Advertise(advertise), _)) => {
+                            self.heard_advertise(&advertise);
+                            return Ok((advertise.gw_id, source));
+                        },
+                        _ => ()
+                    }
+                }
+            }).await
+            .map_err(|_| Error::GatewayNotFound)?
+    }
+
+    pub async fn ping(&mut self) -> Result<(), Error>{
+        let client_id = self.client_id.clone();
+        self.ping_with_id(client_id).await
+    }
+
+    /// Ping using a custom client id field, for gateways that repurpose
+    /// the PINGREQ client id for keepalive conventions.
+    pub async fn ping_as(&mut self, client_id: &str) -> Result<(), Error> {
+        let client_id = client_id_from(client_id)?;
+        self.ping_with_id(client_id).await
+    }
+
+    async fn ping_with_id(&mut self, client_id: ClientId) -> Result<(), Error> {
+        debug!("ping");
+        let packet = Message::PingReq(PingReq { client_id });
+        let ack_handler = |msg| {
             match msg {
-                Message::ConnAck(
+                Message::PingResp(_) => AckResult::Success,
+                _ => AckResult::None
+            }
+        };
+
+        self.ping_outstanding = true;
+        let result = self.send_ack(packet, ack_handler).await;
+        self.ping_outstanding = false;
+        result.map(|_| ())
+    }
+
+    /// Wake from sleep to collect the messages the gateway buffered: the
+    /// PINGREQ has the gateway send them, each is delivered to `tx`, and its
+    /// PINGRESP marks the end. The client is asleep again afterwards.
+    pub async fn wake(&mut self) -> Result<(), Error> {
+        debug!("wake");
+        self.state = ConnectionState::Awake;
+        let result = self.ping().await;
+        // Unless the gateway ended the session meanwhile
+        if self.state == ConnectionState::Awake {
+            self.state = ConnectionState::Asleep;
+        }
+        result
+    }
+
+    /// Send a single PINGREQ without retries and measure the time until the
+    /// PINGRESP. Returns `None` if no response arrived within the retry timeout.
+    pub async fn ping_rtt(&mut self) -> Result<Option<Duration>, Error> {
+        let packet = Message::PingReq(PingReq {
+            client_id: self.client_id.clone()
+        });
+        let start = Instant::now();
+        self.send(packet).await?;
+        self.ping_outstanding = true;
+        let result = with_timeout(
+            self.t_retry,
+            async {
+                loop {
+                    match self.receive().await {
+                        Ok(Some(Message::PingResp(_))) => return Ok(()),
+                        Err(e @ (Error::Disconnected | Error::SocketError | Error::HandshakeFailed)) => {
+                            return Err(e);
+                        },
+                        _ => (),
+                    }
+                }
+            }).await;
+        self.ping_outstanding = false;
+        match result {
+            Ok(Ok(())) => Ok(Some(start.elapsed())),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Send `count` pings and collect RTT and packet loss statistics
+    pub async fn link_quality(&mut self, count: u8) -> Result<LinkStats, Error> {
+        let mut stats = LinkStats {
+            sent: 0, lost: 0, min_rtt: None, max_rtt: None, avg_rtt: None
+        };
+        let mut total = Duration::from_ticks(0);
+        for _ in 0..count {
+            stats.sent += 1;
+            match self.ping_rtt().await? {
+                Some(rtt) => {
+                    total = total + rtt;
+                    stats.min_rtt = Some(stats.min_rtt.map_or(rtt, |min| min.min(rtt)));
+                    stats.max_rtt = Some(stats.max_rtt.map_or(rtt, |max| max.max(rtt)));
+                },
+                None => stats.lost += 1,
+            }
+        }
+        let received = stats.sent - stats.lost;
+        if received > 0 {
+            stats.avg_rtt = Some(total / received as u32);
+        }
+        Ok(stats)
+    }
+
+    /// Publish, registering the topic if needed. Returns the topic id the
+    /// message went out on, which later publishes to the topic reuse.
+    pub async fn publish(&mut self, msg: MqttMessage) -> Result<Option<u16>, Error> {
+        debug!("publish");
+        let (topic_type, topic_id) = self.resolve_topic(msg.topic.clone()).await?;
+        let topic_id = match self.publish_id(topic_type, topic_id, &msg.payload, msg.qos, msg.retain).await {
+            // The gateway lost the registration, register again and retry once
+            Err(Error::TopicNotRegistered) if topic_type == TopicIdType::Id => {
+                let (topic_type, topic_id) = self.resolve_topic(msg.topic).await?;
+                self.publish_id(topic_type, topic_id, &msg.payload, msg.qos, msg.retain).await?
+            },
+            result => result?,
+        };
+        Ok(Some(topic_id))
+    }
+
+    /// Publish at QoS -1: a single PUBLISH without CONNECT and without any
+    /// acknowledgement. Only predefined and short topics can be used, as
+    /// there is no session to register a topic in. `msg.qos` is ignored.
+    pub async fn publish_no_connect(&mut self, msg: MqttMessage) -> Result<(), Error> {
+        debug!("publish without connection");
+        let (topic_type, topic_id) = match self.topics.get_by_topic(&msg.topic) {
+            Some((TopicIdType::Id, _)) => return Err(Error::RegistrationRequired),
+            Some((topic_type, id)) => (*topic_type, *id),
+            None => (
+                TopicIdType::Short,
+                short_topic_id(&msg.topic).ok_or(Error::RegistrationRequired)?
+            ),
+        };
+        let mut flags = Flags::default();
+        // QoS -1 is encoded as 0b11
+        flags.set_qos(3);
+        flags.set_topic_id_type(topic_type as u8);
+
+        let mut data = PublishData::new();
+        data.push_str(&msg.payload)?;
+        self.send(Message::Publish(Publish {
+            flags, topic_id, msg_id: 0, data
+        })).await
+    }
+
+    /// Publish and report how long registration and acknowledgement took
+    pub async fn publish_detailed(&mut self, msg: MqttMessage) -> Result<PublishTiming, Error> {
+        debug!("publish detailed");
+        let start = Instant::now();
+        let (topic_type, topic_id) = self.resolve_topic(msg.topic).await?;
+        let resolved = Instant::now();
+        self.retries = 0;
+        self.publish_id(topic_type, topic_id, &msg.payload, msg.qos, msg.retain).await?;
+        Ok(PublishTiming {
+            register_ms: (resolved - start).as_millis(),
+            ack_wait_ms: resolved.elapsed().as_millis(),
+            retries: self.retries,
+        })
+    }
+
+    /// Resolve a topic to a handle, registering it if needed, for use with
+    /// `publish_to`
+    pub async fn topic_handle(&mut self, topic: &str) -> Result<TopicHandle, Error> {
+        let (topic_type, topic_id) = self.resolve_topic(String::try_from(topic)?).await?;
+        Ok(TopicHandle {
+            topic_type, topic_id,
+            generation: self.topics.generation()
+        })
+    }
+
+    /// Publish on a previously resolved topic, skipping the topic lookup.
+    /// Fails with `TopicNotRegistered` if the handle predates a reset of the
+    /// topic table or the gateway moving the topic to another id.
+    pub async fn publish_to(
+        &mut self, handle: &TopicHandle, payload: &str, qos: Option<u8>, retain: bool
+    ) -> Result<(), Error> {
+        debug!("publish to {}", handle.topic_id);
+        if handle.generation != self.topics.generation() {
+            return Err(Error::TopicNotRegistered);
+        }
+        self.publish_id(handle.topic_type, handle.topic_id, payload, qos, retain).await?;
+        Ok(())
+    }
+
+    async fn resolve_topic(&mut self, topic: String<256>) -> Result<(TopicIdType, u16), Error> {
+        if let Some((topic_type, id)) = self.topics.get_by_topic(&topic) {
+            return Ok((*topic_type, *id));
+        }
+        if let Some(id) = short_topic_id(&topic) {
+            return Ok((TopicIdType::Short, id));
+        }
+        let topic_id = self.register(&topic).await?;
+        // Bursts to many new topics overflow the table, recycle the oldest
+        // registration rather than failing a publish that is already registered
+        if let Err(e) = self.topics.insert_evicting(topic, TopicIdType::Id, topic_id, |_| false) {
+            warn!("publishing without caching topic id {}: {:?}", topic_id, e);
+        }
+        Ok((TopicIdType::Id, topic_id))
+    }
+
+    async fn publish_id(
+        &mut self,
+        topic_type: TopicIdType,
+        topic_id: u16,
+        payload: &str,
+        qos: Option<u8>,
+        retain: bool
+    ) -> Result<u16, Error> {
+        let mut qos = match (qos, self.max_qos) {
+            (Some(qos), Some(max_qos)) if qos > max_qos => Some(self.downgrade_qos(max_qos)?),
+            (qos, _) => qos,
+        };
+
+        loop {
+            let mut flags = Flags::default();
+            if let Some(qos) = qos {
+                flags.set_qos(qos)
+            }
+            flags.set_retain(retain);
+            flags.set_topic_id_type(topic_type as u8);
+            let next_msg_id = self.msg_id.next();
+
+            let mut data = PublishData::new();
+            data.push_str(payload)?;
+            let packet = Message::Publish(
+                Publish {flags, topic_id, msg_id: next_msg_id, data}
+            );
+
+            // Get ACK for QoS 1 & 2
+            match qos {
+                Some(level) if level > 0 && self.inflight_limit > 1 => {
+                    self.drain_inflight(usize::from(self.inflight_limit) - 1).await?;
+                    self.send(packet.clone()).await?;
+                    // Can't fail, the window was just drained below the limit
+                    let _ = self.inflight.push(InFlight {
+                        msg_id: next_msg_id, packet, sent: Instant::now(), attempts: 1
+                    });
+                },
+                Some(level) if level > 0 => {
+                    let result = if level == 2 {
+                        self.publish_exactly_once(packet, next_msg_id).await?
+                    } else {
+                        let ack_handler = |msg| {
+                            match msg {
+                                Message::PubAck(PubAck {
+                                    msg_id, topic_id, code: ReturnCode::Accepted
+                                }) if msg_id == next_msg_id => AckResult::TopicId(topic_id),
+                                Message::PubAck(PubAck {
+                                    msg_id,
+                                    code: code @ ReturnCode::Rejected(
+                                        RejectedReason::NotSupported | RejectedReason::InvalidTopicId
+                                    ),
+                                    ..
+                                }) if msg_id == next_msg_id => AckResult::Rejected(code),
+                                Message::PubAck(PubAck { msg_id, .. }) if msg_id != next_msg_id => {
+                                    warn!("discarding stale PubAck for msg_id {}", msg_id);
+                                    AckResult::None
+                                },
+                                _ => AckResult::None
+                            }
+                        };
+                        self.send_ack(packet, ack_handler).await?
+                    };
+                    match result {
+                        AckResult::Rejected(ReturnCode::Rejected(RejectedReason::InvalidTopicId)) => {
+                            warn!("gateway does not know topic id {}", topic_id);
+                            if topic_type == TopicIdType::Id {
+                                let topic = self.topics.get_by_id(topic_type, topic_id).ok()
+                                    .and_then(|topic| String::<256>::try_from(topic).ok());
+                                if let Some(topic) = topic {
+                                    self.topics.remove(&topic);
+                                }
+                            }
+                            return Err(Error::TopicNotRegistered);
+                        },
+                        AckResult::Rejected(_) => {
+                            // Remember what this gateway supports for later publishes
+                            warn!("gateway does not support QoS {}", level);
+                            self.max_qos = Some(level - 1);
+                            qos = Some(self.downgrade_qos(level - 1)?);
+                            continue;
+                        },
+                        AckResult::TopicId(acked_id) if acked_id != topic_id => {
+                            // The gateway remapped the topic, follow it
+                            warn!("published on topic id {} but gateway acked {}", topic_id, acked_id);
+                            if topic_type == TopicIdType::Id {
+                                if let Err(e) = self.topics.remap(topic_type, topic_id, acked_id) {
+                                    warn!("failed to update topic id: {:?}", e);
+                                }
+                                return Ok(acked_id);
+                            }
+                        },
+                        _ => ()
+                    }
+                },
+                _ => {
+                    self.send(packet.into()).await?;
+                },
+            }
+            return Ok(topic_id);
+        }
+    }
+
+    /// Wait until every publish in flight is acknowledged or given up on
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        self.drain_inflight(0).await
+    }
+
+    /// Collect acknowledgements and retransmit until at most `keep`
+    /// publishes are in flight
+    async fn drain_inflight(&mut self, keep: usize) -> Result<(), Error> {
+        while self.inflight.len() > keep {
+            self.retransmit_inflight().await?;
+            let wait = self.inflight.iter()
+                .map(|f| self.t_retry.checked_sub(f.sent.elapsed()).unwrap_or(Duration::from_ticks(0)))
+                .min()
+                .unwrap_or(self.t_retry);
+            self.receive_timeout(wait).await?;
+        }
+        Ok(())
+    }
+
+    /// Retransmit the publishes in flight whose ack is overdue, giving up on
+    /// those sent `n_retry` times
+    async fn retransmit_inflight(&mut self) -> Result<(), Error> {
+        let mut i = 0;
+        while i < self.inflight.len() {
+            if self.inflight[i].sent.elapsed() < self.t_retry {
+                i += 1;
+                continue;
+            }
+            if self.inflight[i].attempts >= self.n_retry {
+                let entry = self.inflight.remove(i);
+                warn!("no ack for msg_id {}, giving up", entry.msg_id);
+                self.metrics.timeouts = self.metrics.timeouts.wrapping_add(1);
+                self.record_error(Error::AckError);
+                if let Some(handler) = self.on_ack_exhausted.as_mut() {
+                    handler(AckKind::from(&entry.packet));
+                }
+                continue;
+            }
+            let entry = &mut self.inflight[i];
+            entry.attempts += 1;
+            entry.sent = Instant::now();
+            set_dup(&mut entry.packet);
+            let packet = entry.packet.clone();
+            self.metrics.retransmissions = self.metrics.retransmissions.wrapping_add(1);
+            self.send(packet).await?;
+            i += 1;
+        }
+        Ok(())
+    }
+
+    /// Advance the publish in flight that `msg` acknowledges, if any.
+    /// Returns whether `msg` was such an acknowledgement.
+    async fn inflight_ack(&mut self, msg: &Message) -> Result<bool, Error> {
+        let i = match self.inflight.iter().position(|f| is_ack_for(&f.packet, msg)) {
+            Some(i) => i,
+            None => return Ok(false),
+        };
+        match msg {
+            Message::PubRec(PubRec { msg_id }) => {
+                let packet = Message::PubRel(PubRel { msg_id: *msg_id });
+                let entry = &mut self.inflight[i];
+                entry.packet = packet.clone();
+                entry.sent = Instant::now();
+                entry.attempts = 1;
+                self.send(packet).await?;
+            },
+            Message::PubAck(PubAck { topic_id, code: ReturnCode::Rejected(reason), .. }) => {
+                self.inflight.remove(i);
+                warn!("gateway rejected publish on topic id {}", topic_id);
+                self.record_error(match reason {
+                    RejectedReason::InvalidTopicId => Error::TopicNotRegistered,
+                    RejectedReason::NotSupported => Error::QoSNotSupported,
+                    _ => Error::Congestion,
+                });
+            },
+            _ => {
+                self.inflight.remove(i);
+                self.metrics.acks_received = self.metrics.acks_received.wrapping_add(1);
+            },
+        }
+        Ok(true)
+    }
+
+    /// QoS 2 flow: PUBLISH until PUBREC, then PUBREL until PUBCOMP. Both
+    /// legs use the msg_id of the PUBLISH, including retransmitted PUBRELs.
+    async fn publish_exactly_once(
+        &mut self, packet: Message, msg_id: u16
+    ) -> Result<AckResult, Error> {
+        let ack_handler = |msg| {
+            match msg {
+                Message::PubRec(PubRec { msg_id: ack_id }) if ack_id == msg_id => AckResult::Success,
+                Message::PubAck(PubAck {
+                    msg_id: ack_id,
+                    code: code @ ReturnCode::Rejected(
+                        RejectedReason::NotSupported | RejectedReason::InvalidTopicId
+                    ),
+                    ..
+                }) if ack_id == msg_id => AckResult::Rejected(code),
+                _ => AckResult::None
+            }
+        };
+        if let AckResult::Rejected(code) = self.send_ack(packet, ack_handler).await? {
+            return Ok(AckResult::Rejected(code));
+        }
+
+        let packet = Message::PubRel(PubRel { msg_id });
+        let ack_handler = |msg| {
+            match msg {
+                Message::PubComp(PubComp { msg_id: ack_id }) if ack_id == msg_id => AckResult::Success,
+                _ => AckResult::None
+            }
+        };
+        self.send_ack(packet, ack_handler).await
+    }
+
+    fn downgrade_qos(&self, supported: u8) -> Result<u8, Error> {
+        match self.qos_policy {
+            QosPolicy::Downgrade => Ok(supported),
+            QosPolicy::Fail => Err(Error::QoSNotSupported),
+        }
+    }
+
+    /// Register a topic with the gateway. Registrations are serialized by
+    /// `&mut self`, each completes (or fails) before the next msg_id is taken,
+    /// so a burst of new topics can't collide on msg_ids or flood the gateway.
+    async fn register(&mut self, topic: &String<256>) -> Result<u16, Error> {
+        debug!("register");
+        if let Err(e) = validate_topic_len(topic) {
+            warn!("topic of {} bytes is too long to register", topic.len());
+            return Err(e);
+        }
+        let msg_id = self.msg_id.next();
+        let packet = Message::Register(Register {
+            topic_id: 0,
+            msg_id,
+            topic_name: TopicName::from(&topic)
+        });
+        let ack_handler = |msg| {
+            match msg {
+                Message::RegAck(RegAck {
+                    topic_id, msg_id: ack_id, code: ReturnCode::Accepted, ..
+                }) if ack_id == msg_id => AckResult::TopicId(topic_id),
+                Message::RegAck(RegAck {
+                    msg_id: ack_id, code, ..
+                }) if ack_id == msg_id => AckResult::Rejected(code),
+                Message::RegAck(RegAck { msg_id: ack_id, .. }) => {
+                    warn!("discarding stale RegAck for msg_id {}", ack_id);
+                    AckResult::None
+                },
+                _ => AckResult::None
+            }
+        };
+        
+        match self.send_ack(packet, ack_handler).await? {
+            AckResult::TopicId(id) => Ok(id),
+            AckResult::Rejected(code) => Err(Error::RegisterRejected(code)),
+            _ => Err(Error::AckError)
+        }
+    }
+
+    /// Connect with a keep-alive of `duration` seconds. The gateway drops
+    /// the client if it stays silent for longer, but with a persistent
+    /// session (see `set_clean_session`) registrations and subscriptions
+    /// outlive that and are resumed by the next connect.
+    pub async fn connect(&mut self, duration: u16) -> Result<(), Error> {
+        debug!("connect");
+        self.connect_will(duration, None).await
+    }
+
+    /// Connect, choosing the clean-session flag for this and later connects.
+    /// A clean session drops the locally cached topic registrations along
+    /// with the gateway's, a persistent one keeps them for reuse.
+    pub async fn connect_opts(&mut self, opts: ConnectOptions) -> Result<(), Error> {
+        self.set_clean_session(opts.clean_session);
+        self.connect(opts.duration).await
+    }
+
+    /// Connect and register a last will, which the gateway publishes if the
+    /// client goes away without disconnecting. `will_qos` and `retain` go in
+    /// the WILLTOPIC flags, a will can't use QoS -1.
+    pub async fn connect_with_will(
+        &mut self,
+        duration: u16,
+        will_topic: &str,
+        will_msg: &str,
+        will_qos: u8,
+        retain: bool
+    ) -> Result<(), Error> {
+        debug!("connect with will");
+        if will_qos > 2 {
+            return Err(Error::QoSNotSupported);
+        }
+        let mut topic = TopicName::new();
+        topic.push_str(will_topic)?;
+        let mut msg = PublishData::new();
+        msg.push_str(will_msg)?;
+        self.connect_will(duration, Some((topic, msg, will_qos, retain))).await
+    }
+
+    /// CONNECT, answering the gateway's WILLTOPICREQ and WILLMSGREQ if a
+    /// will is given
+    async fn connect_will(
+        &mut self,
+        duration: u16,
+        will: Option<(TopicName, PublishData, u8, bool)>
+    ) -> Result<(), Error> {
+        let mut flags = Flags::default();
+        flags.set_clean_session(self.clean_session);
+        flags.set_will(will.is_some());
+        let packet = Message::Connect(Connect {
+            flags,
+            duration,
+            client_id: self.client_id.clone()
+        });
+        let ack_handler = |msg| {
+            match (msg, &will) {
+                (Message::ConnAck(
                     ConnAck{code: ReturnCode::Accepted}
-                ) => AckResult::Success,
+                ), _) => AckResult::Success,
+                (Message::ConnAck(ConnAck { code }), _) => AckResult::Rejected(code),
+                (Message::WillTopicReq(_), Some((will_topic, _, qos, retain))) => {
+                    let mut flags = Flags::default();
+                    flags.set_qos(*qos);
+                    flags.set_retain(*retain);
+                    AckResult::Reply(Message::WillTopic(WillTopic {
+                        flags,
+                        will_topic: will_topic.clone()
+                    }))
+                },
+                (Message::WillMsgReq(_), Some((_, will_msg, _, _))) => {
+                    AckResult::Reply(Message::WillMsg(WillMsg {
+                        will_msg: will_msg.clone()
+                    }))
+                },
+                _ => AckResult::None
+            }
+        };
+
+        if let AckResult::Rejected(code) = self.send_ack(packet, ack_handler).await? {
+            return Err(Error::ConnectionRejected(code));
+        }
+        if self.clean_session {
+            self.topics.clear_registered();
+            self.sub_topics.clear_registered();
+        }
+        self.keep_alive = duration;
+        self.state = ConnectionState::Connected;
+        Ok(())
+    }
+
+    /// Subscribe to each of `topics` at QoS 0, returning the granted QoS and
+    /// topic id of each in order. Stops at the first failure, the topics
+    /// before it stay subscribed and are returned with the error, so the
+    /// first `granted.len()` topics went through.
+    pub async fn subscribe_all(&mut self, topics: &[&str]) -> Result<Granted, (Granted, Error)> {
+        let mut granted = Granted::new();
+        if topics.len() > granted.capacity() {
+            return Err((granted, Error::TopicTableFull));
+        }
+        for topic in topics {
+            match self.subscribe(topic, 0).await {
+                // Can't overflow, the length was checked above
+                Ok(result) => { let _ = granted.push(result); },
+                Err(e) => {
+                    warn!("subscribe_all failed after {} of {} topics", granted.len(), topics.len());
+                    return Err((granted, e));
+                },
+            }
+        }
+        Ok(granted)
+    }
+
+    /// Subscribe to a topic or, if it contains `+` or `#`, to a wildcard
+    /// filter. Normal topics and filters go by name, predefined and short
+    /// topics by id. The gateway assigns a normal topic its id in the SUBACK,
+    /// and registers each topic matching a filter before publishing on it.
+    /// Returns the QoS granted by the gateway, which may be lower than `qos`,
+    /// and the topic id, which is 0 for a filter.
+    pub async fn subscribe(&mut self, topic: &str, qos: u8) -> Result<(u8, u16), Error> {
+        debug!("subscribe");
+        validate_filter(topic)?;
+        if let Err(e) = validate_topic_len(topic) {
+            warn!("topic of {} bytes is too long to subscribe", topic.len());
+            return Err(e);
+        }
+        let wildcard = topic.contains(|c| c == '+' || c == '#');
+        let topic = String::<256>::try_from(topic)?;
+        // Refuse before the gateway grants a subscription we couldn't renew
+        if self.subscriptions.is_full() && !self.subscriptions.iter().any(|(s, _)| *s == topic) {
+            warn!("too many subscriptions to subscribe to another topic");
+            return Err(Error::TopicFailedInsert);
+        }
+        let (topic_type, field) = self.topic_field(&topic);
+        // The topic id from the SUBACK needs a place in the inbound table,
+        // or publishes on it couldn't be told apart
+        let subscriptions = &self.subscriptions;
+        if topic_type == TopicIdType::Id && !wildcard && !self.sub_topics.has_room(
+            &topic, |t| subscriptions.iter().any(|(s, _)| s == t)
+        ) {
+            warn!("inbound topic table full of subscribed topics");
+            return Err(Error::TopicFailedInsert);
+        }
+        let topic_id = match field {
+            TopicNameOrId::Id(id) => id,
+            _ => 0,
+        };
+        let mut flags = Flags::default();
+        flags.set_topic_id_type(topic_type as u8);
+        flags.set_qos(qos);
+        let msg_id = self.msg_id.next();
+
+        let packet = Message::Subscribe(Subscribe {
+            flags,
+            msg_id,
+            topic: field,
+        });
+        let ack_handler = |msg| {
+            match msg {
+                Message::SubAck(SubAck {
+                    flags, msg_id: ack_id, topic_id, code: ReturnCode::Accepted
+                }) if ack_id == msg_id => AckResult::Granted(flags.qos(), topic_id),
+                Message::SubAck(SubAck { msg_id: ack_id, .. }) if ack_id != msg_id => {
+                    warn!("discarding stale SubAck for msg_id {}", ack_id);
+                    AckResult::None
+                },
                 _ => AckResult::None
             }
-        };
-
-        self.send_ack(packet, ack_handler).await?;
-        Ok(())
+        };
+
+        let (granted_qos, assigned_id) = match self.send_ack(packet, ack_handler).await? {
+            AckResult::Granted(granted_qos, id) if id != 0 => (granted_qos, id),
+            AckResult::Granted(granted_qos, _) => (granted_qos, topic_id),
+            _ => (qos, topic_id)
+        };
+
+        // Inbound publishes resolve through this table. A full table must not
+        // fail the subscription, make room by evicting an unsubscribed topic.
+        // Matches of a filter are added as the gateway registers them.
+        let subscriptions = &self.subscriptions;
+        let assigned_id = if wildcard { 0 } else { assigned_id };
+        // Short topics are decoded from the id itself
+        if assigned_id != 0 && topic_type != TopicIdType::Short {
+            if let Err(e) = self.sub_topics.insert_evicting(
+                topic.clone(), topic_type, assigned_id,
+                |t| subscriptions.iter().any(|(s, _)| s == t)
+            ) {
+                warn!("subscribed without caching topic id {}: {:?}", assigned_id, e);
+            }
+        }
+        match self.subscriptions.iter_mut().find(|(s, _)| *s == topic) {
+            Some((_, requested)) => *requested = qos,
+            // Can't fail, there was room before subscribing
+            None => { let _ = self.subscriptions.push((topic, qos)); },
+        }
+        Ok((granted_qos, assigned_id))
+    }
+
+    /// Stop receiving on a subscribed topic or wildcard filter, referred to
+    /// the same way `subscribe` did. Its inbound topic id is forgotten, a
+    /// registration used for publishing is kept.
+    pub async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        debug!("unsubscribe");
+        validate_topic_len(topic)?;
+        let (topic_type, field) = self.topic_field(topic);
+        self.unsubscribe_topic(topic_type, field).await?;
+        if topic_type == TopicIdType::Id {
+            self.sub_topics.remove(topic);
+        }
+        self.subscriptions.retain(|(s, _)| s != topic);
+        Ok(())
+    }
+
+    /// Unsubscribe by the topic id the gateway assigned, e.g. in SUBACK.
+    /// A predefined id is sent as such, a normal one by the topic name it
+    /// stands for, which must be known.
+    pub async fn unsubscribe_id(&mut self, topic_id: u16) -> Result<(), Error> {
+        debug!("unsubscribe {}", topic_id);
+        let topic = match self.sub_topics.get_by_id(TopicIdType::Id, topic_id) {
+            Ok(topic) => topic,
+            Err(_) => self.sub_topics.get_by_id(TopicIdType::PreDef, topic_id)?,
+        };
+        let topic = String::<256>::try_from(topic)?;
+        self.unsubscribe(&topic).await
+    }
+
+    /// How SUBSCRIBE and UNSUBSCRIBE refer to `topic`. Predefined and short
+    /// topics go by id, anything else, filters included, by name, for which
+    /// the topic id type is that of a normal topic.
+    fn topic_field(&self, topic: &str) -> (TopicIdType, TopicNameOrId) {
+        let predefined = self.sub_topics.get_by_topic(topic)
+            .or(self.topics.get_by_topic(topic))
+            .filter(|(topic_type, _)| *topic_type == TopicIdType::PreDef);
+        if let Some((_, id)) = predefined {
+            return (TopicIdType::PreDef, TopicNameOrId::Id(*id));
+        }
+        let wildcard = topic.contains(|c| c == '+' || c == '#');
+        match short_topic_id(topic) {
+            Some(id) if !wildcard => (TopicIdType::Short, TopicNameOrId::Id(id)),
+            _ => (TopicIdType::Id, TopicNameOrId::Name(TopicName::from(topic))),
+        }
+    }
+
+    async fn unsubscribe_topic(
+        &mut self, topic_type: TopicIdType, topic: TopicNameOrId
+    ) -> Result<(), Error> {
+        let msg_id = self.msg_id.next();
+        let mut flags = Flags::default();
+        flags.set_topic_id_type(topic_type as u8);
+
+        let packet = Message::Unsubscribe(Unsubscribe {
+            flags,
+            msg_id,
+            topic,
+        });
+        let ack_handler = |msg| {
+            match msg {
+                Message::UnsubAck(UnsubAck {
+                    msg_id: ack_id
+                }) if ack_id == msg_id => AckResult::Success,
+                Message::UnsubAck(UnsubAck { msg_id: ack_id }) => {
+                    warn!("discarding stale UnsubAck for msg_id {}", ack_id);
+                    AckResult::None
+                },
+                _ => AckResult::None
+            }
+        };
+
+        self.send_ack(packet, ack_handler).await?;
+        Ok(())
+    }
+
+    /// Connect again after the session was lost. With a clean session the
+    /// gateway starts from scratch, so topics are registered and
+    /// subscriptions renewed before anything is published. A persistent
+    /// session keeps both, and a registration the gateway lost anyway is
+    /// redone by the next publish to it.
+    pub async fn reconnect(&mut self, duration: u16) -> Result<(), Error> {
+        debug!("reconnect");
+        let registered = self.registered_topics();
+        self.connect(duration).await?;
+        if self.clean_session {
+            self.renew_session(registered).await?;
+        }
+        Ok(())
+    }
+
+    /// Names of the topics registered for publishing
+    fn registered_topics(&self) -> heapless::Vec<String<256>, 16> {
+        self.topics.iter()
+            .filter(|(_, topic_type, _)| *topic_type == TopicIdType::Id)
+            .filter_map(|(topic, _, _)| String::try_from(topic).ok())
+            .collect()
+    }
+
+    /// Register `registered` again and renew subscriptions on a connection
+    /// that doesn't know them
+    async fn renew_session(
+        &mut self, registered: heapless::Vec<String<256>, 16>
+    ) -> Result<(), Error> {
+        self.topics.clear_registered();
+        self.sub_topics.clear_registered();
+        for topic in registered {
+            self.resolve_topic(topic).await?;
+        }
+        self.resubscribe().await
+    }
+
+    /// Subscribe again to every topic subscribed in this session
+    async fn resubscribe(&mut self) -> Result<(), Error> {
+        for i in 0..self.subscriptions.len() {
+            let (topic, qos) = self.subscriptions[i].clone();
+            self.subscribe(&topic, qos).await?;
+        }
+        Ok(())
+    }
+
+    /// Change the will topic of the session. An empty topic deletes the
+    /// will, `qos` and `retain` are then ignored.
+    pub async fn update_will_topic(
+        &mut self, topic: &str, qos: u8, retain: bool
+    ) -> Result<(), Error> {
+        debug!("update will topic");
+        if qos > 2 {
+            return Err(Error::QoSNotSupported);
+        }
+        let mut flags = Flags::default();
+        if !topic.is_empty() {
+            flags.set_qos(qos);
+            flags.set_retain(retain);
+        }
+        let mut will_topic = TopicName::new();
+        will_topic.push_str(topic)?;
+        let packet = Message::WillTopicUpd(WillTopicUpd {
+            flags,
+            will_topic
+        });
+        let ack_handler = |msg| {
+            match msg {
+                Message::WillTopicResp(WillTopicResp {
+                    code: ReturnCode::Accepted
+                }) => AckResult::Success,
+                Message::WillTopicResp(WillTopicResp { code }) => AckResult::Rejected(code),
+                _ => AckResult::None
+            }
+        };
+
+        match self.send_ack(packet, ack_handler).await? {
+            AckResult::Rejected(code) => Err(Error::WillRejected(code)),
+            _ => Ok(())
+        }
+    }
+
+    /// Change the will message of the session
+    pub async fn update_will_message(&mut self, payload: &str) -> Result<(), Error> {
+        debug!("update will message");
+        let mut will_msg = PublishData::new();
+        will_msg.push_str(payload)?;
+        let packet = Message::WillMsgUpd(WillMsgUpd { will_msg });
+        let ack_handler = |msg| {
+            match msg {
+                Message::WillMsgResp(WillMsgResp {
+                    code: ReturnCode::Accepted
+                }) => AckResult::Success,
+                Message::WillMsgResp(WillMsgResp { code }) => AckResult::Rejected(code),
+                _ => AckResult::None
+            }
+        };
+
+        match self.send_ack(packet, ack_handler).await? {
+            AckResult::Rejected(code) => Err(Error::WillRejected(code)),
+            _ => Ok(())
+        }
+    }
+
+    /// If duration is set, then client will go to sleep, with keep-alive < duration
+    pub async fn disconnect(&mut self, duration: Option<u16>) -> Result<(), Error> {
+        debug!("disconnect");
+        // Set before sending so the gateway's reply isn't taken as unsolicited
+        self.state = match duration {
+            Some(_) => ConnectionState::Asleep,
+            None => ConnectionState::Disconnected,
+        };
+        let packet = Message::Disconnect(Disconnect {
+            duration
+        });
+        let ack_handler = |msg| {
+            match msg {
+                Message::Disconnect(_) => AckResult::Success,
+                _ => AckResult::None
+            }
+        };
+
+        self.send_ack(packet, ack_handler).await?;
+        Ok(())
+    }
+
+    /// End the session with a single DISCONNECT, waiting at most `timeout`
+    /// for the gateway to confirm. Meant for teardown, where a gateway that
+    /// is already gone shouldn't hold things up, so a missing reply is not
+    /// an error.
+    pub async fn disconnect_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        debug!("disconnect with timeout");
+        self.state = ConnectionState::Disconnected;
+        self.send(Message::Disconnect(Disconnect { duration: None })).await?;
+        let _ = with_timeout(timeout, async {
+            loop {
+                match self.receive_timeout(timeout).await {
+                    Ok(Some(Message::Disconnect(_))) | Ok(None) | Err(_) => return,
+                    Ok(Some(_)) => (),
+                }
+            }
+        }).await;
+        Ok(())
+    }
+}
+
+impl<S, B> MqttSnClient<S, B>
+where
+    S: SendBytes + ReceiveBytes + Reconnect + ConnectTo,
+    B: AsMut<[u8]>
+{
+    pub async fn run(
+        &mut self,
+        sleep: u16,
+    ) {
+        loop {
+            if self.shutdown_requested() {
+                if let Err(e) = self.shutdown().await {
+                    warn!("shutdown: {:?}", e);
+                    self.record_error(e);
+                }
+                return;
+            }
+            if self.gateway_lost() {
+                warn!("gateway stopped advertising, reconnecting");
+                self.last_advertise = None;
+                self.state = ConnectionState::Disconnected;
+                if let Some(handler) = self.on_disconnect.as_mut() {
+                    handler(DisconnectReason::GatewayLost);
+                }
+                if let Err(e) = self.recover(sleep).await {
+                    warn!("failed to reconnect: {:?}", e);
+                    self.record_error(e);
+                }
+            }
+            let timeout = self.keep_alive_due()
+                .unwrap_or(Duration::from_secs(sleep.into()));
+            let result = match with_timeout(
+                timeout,
+                self.rx.next_message_pure()
+            ).await {
+                // Handle message received from the user (via DynSubscriber)
+                Ok(msg) => self.publish_queued(msg, sleep).await,
+                _ => self.ping().await,
+            };
+            match result {
+                Err(e @ (Error::SocketError | Error::HandshakeFailed)) => {
+                    warn!("socket failed, reconnecting");
+                    self.record_error(e);
+                    if let Err(e) = self.recover(sleep).await {
+                        warn!("failed to recover socket: {:?}", e);
+                        self.record_error(e);
+                    }
+                },
+                Err(e) => {
+                    warn!("run: {:?}", e);
+                    self.record_error(e);
+                },
+                Ok(_) => (),
+            }
+        }
+    }
+
+    /// Recover the transport and session, failing over to the gateways from
+    /// `set_gateways` if the current one can't be reached again
+    async fn recover(&mut self, duration: u16) -> Result<(), Error> {
+        match self.recover_socket(duration).await {
+            Err(e) if !self.gateways.is_empty() => {
+                warn!("gateway unreachable, failing over: {:?}", e);
+                self.record_error(e);
+                self.failover(duration).await.map(|_| ())
+            },
+            result => result,
+        }
+    }
+
+    /// Reconnect the transport, backing off between up to `n_retry`
+    /// attempts, then establish the MQTT-SN session again
+    async fn recover_socket(&mut self, duration: u16) -> Result<(), Error> {
+        let mut backoff = self.backoff.initial;
+        for attempt in 1..=self.n_retry {
+            match self.socket.reconnect().await {
+                Ok(()) => return self.connect(duration).await,
+                Err(_) => {
+                    warn!("socket reconnect attempt {} failed", attempt);
+                    Timer::after(backoff).await;
+                    backoff = self.backoff.next(backoff);
+                },
+            }
+        }
+        Err(Error::SocketError)
+    }
+}
+
+impl<S, B> MqttSnClient<S, B>
+where
+    S: SendBytes + ReceiveBytes + ConnectTo,
+    B: AsMut<[u8]>
+{
+    /// Connect to the first gateway from `resolver` that accepts the session,
+    /// starting with the last one that worked. Each candidate gets its own
+    /// transport connection (and handshake, for DTLS).
+    pub async fn connect_any<R>(
+        &mut self, resolver: &mut R, duration: u16
+    ) -> Result<SocketAddr, Error>
+    where
+        R: GatewayResolver + ?Sized
+    {
+        let mut last_error = Error::AckError;
+        if let Some(addr) = self.gateway {
+            match self.connect_to(addr, duration).await {
+                Ok(()) => return Ok(addr),
+                Err(e) => {
+                    warn!("last working gateway failed: {:?}", e);
+                    last_error = e;
+                },
+            }
+        }
+        let mut index = 0;
+        while let Some(addr) = resolver.resolve(index) {
+            index += 1;
+            if Some(addr) == self.gateway {
+                continue;
+            }
+            match self.connect_to(addr, duration).await {
+                Ok(()) => return Ok(addr),
+                Err(e) => {
+                    warn!("gateway candidate {} failed: {:?}", index, e);
+                    last_error = e;
+                },
+            }
+        }
+        Err(last_error)
     }
 
-    pub async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
-        debug!("subscribe");
-        let mut flags = Flags::default();
-        let topic_id;
-        let topic = String::<256>::try_from(topic)?;
-        if let Some((topic_type, id)) = self.topics.get_by_topic(&topic) {
-            topic_id = *id;
-            flags.set_topic_id_type(*topic_type as u8);
-        } else {
-            topic_id = self.register(&topic).await?;
-            self.topics.insert(topic, TopicIdType::Id, topic_id)?;
+    /// Re-establish the session with the active gateway or, failing that, the
+    /// first of the `set_gateways` list to accept it. Topics are registered
+    /// and subscriptions renewed as with `reconnect`, and always when the
+    /// session moved to another gateway.
+    pub async fn failover(&mut self, duration: u16) -> Result<SocketAddr, Error> {
+        debug!("failover");
+        let previous = self.gateway;
+        let registered = self.registered_topics();
+        let mut gateways = self.gateways.clone();
+        let addr = self.connect_any(&mut gateways[..], duration).await?;
+        if self.clean_session || previous != Some(addr) {
+            self.renew_session(registered).await?;
         }
-        let msg_id = self.msg_id.next();
-        let mut flags = Flags::default();
-        flags.set_topic_id_type(1);
-
-        let packet = Message::Subscribe(Subscribe {
-            flags,
-            msg_id,
-            topic: TopicNameOrId::Id(topic_id),
-        });
-        let ack_handler = |msg| {
-            match msg {
-                Message::SubAck(SubAck {
-                    code: ReturnCode::Accepted, ..
-                }) => AckResult::Success,
-                _ => AckResult::None
-            }
-        };
+        Ok(addr)
+    }
 
-        self.send_ack(packet, ack_handler).await?;
+    async fn connect_to(&mut self, addr: SocketAddr, duration: u16) -> Result<(), Error> {
+        self.socket.connect(addr).await?;
+        if self.gateway != Some(addr) {
+            self.gateway_changed();
+        }
+        self.connect(duration).await?;
+        self.gateway = Some(addr);
         Ok(())
     }
 
-    /// If duration is set, then client will go to sleep, with keep-alive < duration
-    pub async fn disconnect(&mut self, duration: Option<u16>) -> Result<(), Error> {
-        debug!("disconnect");
-        let packet = Message::Disconnect(Disconnect {
-            duration
-        });
-        let ack_handler = |msg| {
-            match msg {
-                Message::Disconnect(_) => AckResult::Success,
-                _ => AckResult::None
-            }
-        };
+    /// Move the session to another gateway. The current gateway gets a
+    /// best-effort DISCONNECT, the transport is pointed at `addr`, and the
+    /// session is re-established with the last keep-alive. Registered topic
+    /// ids are dropped (they are re-registered on use) and subscriptions are
+    /// renewed.
+    pub async fn switch_gateway(&mut self, addr: SocketAddr) -> Result<(), Error> {
+        debug!("switch gateway");
+        if let Err(e) = self.send(Message::Disconnect(Disconnect { duration: None })).await {
+            warn!("failed to disconnect from previous gateway: {:?}", e);
+        }
+        self.socket.connect(addr).await?;
+        self.gateway_changed();
+        self.connect(self.keep_alive).await?;
+        self.gateway = Some(addr);
+        self.resubscribe().await
+    }
 
-        self.send_ack(packet, ack_handler).await?;
-        Ok(())
+    /// Forget what was learned about the previous gateway: its topic ids
+    /// and the QoS it supports
+    fn gateway_changed(&mut self) {
+        self.topics.clear_registered();
+        self.sub_topics.clear_registered();
+        self.max_qos = None;
     }
 }
 
@@ -320,33 +2482,48 @@ pub struct MqttMessage {
     topic_id: Option<u16>,
     msg_id: Option<u16>,
     qos: Option<u8>,
+    /// Ask the gateway to keep the message for future subscribers, or on
+    /// receive, a retained message delivered on subscribe
+    pub retain: bool,
     pub topic: String<256>,
     pub payload: String<256>,
 }
 
 impl MqttMessage {
+    /// Fails with `MessageTooLarge` if the topic or payload exceeds 256 bytes
     pub fn new(
         topic: &str,
         payload: &str,
-        qos: Option<u8>
+        qos: Option<u8>,
+        retain: bool
     ) -> Result<Self, Error> {
         Ok(Self {
             topic_id: None,
             msg_id: None,
-            topic: String::try_from(topic)?,
-            payload: String::try_from(payload)?,
-            qos
+            topic: String::try_from(topic).map_err(|_| Error::MessageTooLarge)?,
+            payload: String::try_from(payload).map_err(|_| Error::MessageTooLarge)?,
+            qos, retain
         })
     }
     fn from_publish(
         msg: Publish,
         topics: &Topics,
     ) -> Result<Self, Error> {
+        let topic_type = TopicIdType::try_from(msg.flags.topic_id_type())?;
+        let topic = match topic_type {
+            // The two characters of the name are the id itself
+            TopicIdType::Short => short_topic_name(msg.topic_id)?,
+            // Agreed beforehand, see `register_predefined`, or registered
+            TopicIdType::PreDef | TopicIdType::Id => {
+                String::try_from(topics.get_by_id(topic_type, msg.topic_id)?)?
+            },
+        };
         Ok(Self {
             topic_id: Some(msg.topic_id),
             msg_id: Some(msg.msg_id),
             qos: Some(msg.flags.qos()),
-            topic: String::try_from(topics.get_by_id(msg.topic_id)?)?,
+            retain: msg.flags.retain(),
+            topic,
             payload: String::try_from(msg.data.as_str())?,
         })
     }
@@ -361,13 +2538,65 @@ impl MqttMessage {
     }
 }
 
+/// Whether `reply` answers `packet` with a congestion rejection
+fn is_congestion(packet: &Message, reply: &Message) -> bool {
+    match (packet, reply) {
+        (Message::Connect(_), Message::ConnAck(ConnAck { code }))
+        | (Message::WillTopicUpd(_), Message::WillTopicResp(WillTopicResp { code }))
+        | (Message::WillMsgUpd(_), Message::WillMsgResp(WillMsgResp { code })) => {
+            matches!(code, ReturnCode::Rejected(RejectedReason::Congestion))
+        },
+        (Message::Register(Register { msg_id, .. }), Message::RegAck(RegAck { msg_id: ack_id, code, .. }))
+        | (Message::Publish(Publish { msg_id, .. }), Message::PubAck(PubAck { msg_id: ack_id, code, .. }))
+        | (Message::Subscribe(Subscribe { msg_id, .. }), Message::SubAck(SubAck { msg_id: ack_id, code, .. })) => {
+            msg_id == ack_id && matches!(code, ReturnCode::Rejected(RejectedReason::Congestion))
+        },
+        _ => false
+    }
+}
+
+/// Whether `reply` is the next acknowledgement in the flow of `packet`
+fn is_ack_for(packet: &Message, reply: &Message) -> bool {
+    match (packet, reply) {
+        (Message::Publish(Publish { msg_id, .. }), Message::PubAck(PubAck { msg_id: ack_id, .. }))
+        | (Message::Publish(Publish { msg_id, .. }), Message::PubRec(PubRec { msg_id: ack_id }))
+        | (Message::PubRel(PubRel { msg_id }), Message::PubComp(PubComp { msg_id: ack_id })) => {
+            msg_id == ack_id
+        },
+        _ => false
+    }
+}
+
+/// Mark a packet as a retransmission. PUBREL has no flags, the gateway
+/// recognizes its retransmission by the msg_id.
+fn set_dup(packet: &mut Message) {
+    match packet {
+        Message::Publish(Publish { flags, .. })
+        | Message::Subscribe(Subscribe { flags, .. }) => flags.set_dup(true),
+        _ => ()
+    }
+}
+
+fn export_topics(topics: &Topics) -> heapless::Vec<TopicEntry, 16> {
+    topics.iter()
+        .filter_map(|(topic, topic_type, id)| Some((String::try_from(topic).ok()?, topic_type, id)))
+        .collect()
+}
+
+fn client_id_from(id: &str) -> Result<ClientId, Error> {
+    let mut client_id = ClientId::new();
+    client_id.push_str(id).map_err(|_| Error::InvalidClientId)?;
+    Ok(client_id)
+}
+
 pub struct MsgId {
     last_id: u16
 }
 
 impl MsgId {
+    /// 0 is reserved, so the id wraps from 65535 to 1
     fn next(&mut self) -> u16 {
-        self.last_id = self.last_id.wrapping_add(1);
+        self.last_id = self.last_id.checked_add(1).unwrap_or(1);
         self.last_id
     }
 }
@@ -377,24 +2606,46 @@ impl MsgId {
 pub enum MqttSnClientError {
     ModemError,
     SocketError,
-    CodecError,
+    CodecError(#[cfg_attr(feature = "no_std", defmt(Debug2Format))] byte::Error),
     AckError,
     UnknownError,
     ParseError,
     TopicNotRegistered,
     TopicFailedInsert,
     NoPingResponse,
+    InvalidClientId,
+    InvalidFilter,
+    InvalidMsgId,
+    InvalidSession,
+    QoSNotSupported,
+    RegistrationRequired,
+    GatewayNotFound,
+    Disconnected,
+    Congestion,
+    MissingChannel,
+    BufferOverflow,
+    TopicTableFull,
+    HandshakeFailed,
+    WillRejected(#[cfg_attr(feature = "no_std", defmt(Debug2Format))] ReturnCode),
+    ConnectionRejected(#[cfg_attr(feature = "no_std", defmt(Debug2Format))] ReturnCode),
+    RegisterRejected(#[cfg_attr(feature = "no_std", defmt(Debug2Format))] ReturnCode),
+    MessageTooLarge,
+    InvalidNodeId,
+    NotDisconnected,
 }
 
 impl From<SocketError> for MqttSnClientError {
-    fn from(_e: SocketError) -> Self {
-        MqttSnClientError::SocketError
+    fn from(e: SocketError) -> Self {
+        match e {
+            SocketError::Handshake => MqttSnClientError::HandshakeFailed,
+            _ => MqttSnClientError::SocketError,
+        }
     }
 }
 
 impl From<byte::Error> for MqttSnClientError {
-    fn from(_e: byte::Error) -> Self {
-        MqttSnClientError::CodecError
+    fn from(e: byte::Error) -> Self {
+        MqttSnClientError::CodecError(e)
     }
 }
 
@@ -409,3 +2660,825 @@ impl From<()> for MqttSnClientError {
         MqttSnClientError::UnknownError
     }
 }
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::test_util::{MemoryLink, MemorySocket, TestGateway};
+    use crate::topics::MAX_TOPIC_LEN;
+    use core::cell::Cell;
+    use core::future::Future;
+    use embassy_futures::join::join;
+    use embassy_futures::select::{select, Either};
+    use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+    use embassy_sync::pubsub::PubSubChannel;
+    use std::rc::Rc;
+
+    type Channel = PubSubChannel<CriticalSectionRawMutex, MqttMessage, 8, 2, 2>;
+
+    const CONNECT: u8 = 0x04;
+    const REGISTER: u8 = 0x0A;
+    const PUBLISH: u8 = 0x0C;
+    const SUBSCRIBE: u8 = 0x12;
+    const UNSUBSCRIBE: u8 = 0x14;
+
+    /// Client and gateway ends of a fresh in-memory link
+    fn link() -> (MemorySocket<'static>, MemorySocket<'static>) {
+        Box::leak(Box::new(MemoryLink::new())).split()
+    }
+
+    /// A client restored from `state` on a link nobody listens on
+    fn restore(state: SessionState) -> Result<MqttSnClient<MemorySocket<'static>>, Error> {
+        let outbound: &'static Channel = Box::leak(Box::new(Channel::new()));
+        let inbound: &'static Channel = Box::leak(Box::new(Channel::new()));
+        MqttSnClient::restore_session(
+            state,
+            "test",
+            outbound.dyn_subscriber().unwrap(),
+            inbound.dyn_publisher().unwrap(),
+            link().0
+        )
+    }
+
+    /// A client with short retries, so that tests of lost packets are quick
+    fn client<S: SendBytes + ReceiveBytes>(socket: S) -> MqttSnClient<S> {
+        let outbound: &'static Channel = Box::leak(Box::new(Channel::new()));
+        let inbound: &'static Channel = Box::leak(Box::new(Channel::new()));
+        MqttSnClient::new(
+            "test",
+            outbound.dyn_subscriber().unwrap(),
+            inbound.dyn_publisher().unwrap(),
+            socket
+        ).unwrap()
+            .with_retry(Duration::from_millis(50), 3)
+    }
+
+    /// Drive `fut` while `gateway` serves the other end of the link
+    async fn with_gateway<F: Future>(gateway: &mut TestGateway<'_>, fut: F) -> F::Output {
+        match select(fut, gateway.run()).await {
+            Either::First(output) => output,
+            Either::Second(()) => unreachable!("the gateway serves forever"),
+        }
+    }
+
+    /// Next packet from the client, as sent
+    async fn next_raw(gateway: &mut MemorySocket<'_>) -> heapless::Vec<u8, 512> {
+        let mut buffer = [0u8; 512];
+        let bytes = gateway.recv(&mut buffer).await.unwrap();
+        heapless::Vec::from_slice(bytes).unwrap()
+    }
+
+    /// Next packet from the client, decoded
+    async fn next_packet(gateway: &mut MemorySocket<'_>) -> Message {
+        let bytes = next_raw(gateway).await;
+        Message::try_read(&bytes, ()).unwrap().0
+    }
+
+    async fn send_packet(gateway: &mut MemorySocket<'_>, msg: Message) {
+        let mut buffer = [0u8; 512];
+        let len = msg.try_write(&mut buffer, ()).unwrap();
+        gateway.send(&buffer[..len]).await.unwrap();
+    }
+
+    fn publish_packet(topic_id: u16, payload: &str) -> Message {
+        let mut data = PublishData::new();
+        data.push_str(payload).unwrap();
+        Message::Publish(Publish { flags: Flags::default(), topic_id, msg_id: 0, data })
+    }
+
+    #[tokio::test]
+    async fn round_trip_through_test_gateway() {
+        let (socket, gateway) = link();
+        let mut gateway = TestGateway::new(gateway);
+        let mut client = client(socket);
+
+        let msg = with_gateway(&mut gateway, async {
+            client.connect(60).await?;
+            client.subscribe("test/echo", 0).await?;
+            client.publish(MqttMessage::new("test/echo", "hello", Some(1), false)?).await?;
+            client.next_message().await
+        }).await.unwrap();
+
+        assert_eq!(client.state(), ConnectionState::Connected);
+        assert_eq!(msg.topic, "test/echo");
+        assert_eq!(msg.payload, "hello");
+        // Subscribed by name, only the publish registered
+        assert_eq!(gateway.count(REGISTER), 1);
+        assert_eq!(gateway.count(SUBSCRIBE), 1);
+        assert_eq!(gateway.count(PUBLISH), 1);
+    }
+
+    #[tokio::test]
+    async fn will_updates_accepted() {
+        let (socket, gateway) = link();
+        let mut gateway = TestGateway::new(gateway);
+        let mut client = client(socket);
+
+        with_gateway(&mut gateway, async {
+            client.connect(60).await?;
+            client.update_will_topic("test/will", 1, true).await?;
+            client.update_will_message("gone").await
+        }).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn will_updates_rejected() {
+        let (socket, gateway) = link();
+        let mut gateway = TestGateway::new(gateway);
+        gateway.set_will_accepted(false);
+        let mut client = client(socket);
+
+        let (topic, msg) = with_gateway(&mut gateway, async {
+            client.connect(60).await.unwrap();
+            (
+                client.update_will_topic("test/will", 1, true).await,
+                client.update_will_message("gone").await,
+            )
+        }).await;
+
+        assert!(matches!(
+            topic,
+            Err(Error::WillRejected(ReturnCode::Rejected(RejectedReason::NotSupported)))
+        ));
+        assert!(matches!(
+            msg,
+            Err(Error::WillRejected(ReturnCode::Rejected(RejectedReason::NotSupported)))
+        ));
+        // A rejection is an answer, not a reason to retransmit
+        assert_eq!(client.metrics().retransmissions, 0);
+    }
+
+    /// Publish on one topic and subscribe to another, then reconnect
+    async fn publish_subscribe_reconnect(
+        client: &mut MqttSnClient<MemorySocket<'static>>
+    ) -> Result<(), Error> {
+        client.connect(60).await?;
+        client.publish(MqttMessage::new("test/out", "1", Some(1), false)?).await?;
+        client.subscribe("test/in", 1).await?;
+        client.reconnect(60).await
+    }
+
+    #[tokio::test]
+    async fn persistent_reconnect_keeps_registrations() {
+        let (socket, gateway) = link();
+        let mut gateway = TestGateway::new(gateway);
+        let mut client = client(socket);
+
+        with_gateway(&mut gateway, publish_subscribe_reconnect(&mut client)).await.unwrap();
+
+        assert!(client.topics().any(|(topic, _, _)| topic == "test/out"));
+        assert!(client.inbound_topics().any(|(topic, _, _)| topic == "test/in"));
+        assert_eq!(gateway.count(REGISTER), 1);
+        assert_eq!(gateway.count(SUBSCRIBE), 1);
+    }
+
+    #[tokio::test]
+    async fn clean_reconnect_registers_again() {
+        let (socket, gateway) = link();
+        let mut gateway = TestGateway::new(gateway);
+        let mut client = client(socket);
+        client.set_clean_session(true);
+
+        with_gateway(&mut gateway, publish_subscribe_reconnect(&mut client)).await.unwrap();
+
+        assert!(client.topics().any(|(topic, _, _)| topic == "test/out"));
+        assert!(client.inbound_topics().any(|(topic, _, _)| topic == "test/in"));
+        assert_eq!(gateway.count(REGISTER), 2);
+        assert_eq!(gateway.count(SUBSCRIBE), 2);
+    }
+
+    #[tokio::test]
+    async fn subscribe_all_reports_how_far_it_got() {
+        let (socket, gateway) = link();
+        let mut gateway = TestGateway::new(gateway);
+        let mut client = client(socket);
+
+        let result = with_gateway(&mut gateway, async {
+            client.connect(60).await.unwrap();
+            client.subscribe_all(&["test/a", "test/b", "test/c#", "test/d"]).await
+        }).await;
+
+        let (granted, e) = result.unwrap_err();
+        assert_eq!(granted.len(), 2);
+        assert!(matches!(e, Error::InvalidFilter));
+        assert_eq!(gateway.count(SUBSCRIBE), 2);
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_wildcard_filter() {
+        let (socket, gateway) = link();
+        let mut gateway = TestGateway::new(gateway);
+        let mut client = client(socket);
+        client.set_clean_session(true);
+
+        with_gateway(&mut gateway, async {
+            client.connect(60).await?;
+            client.subscribe("test/+", 0).await?;
+            client.unsubscribe("test/+").await?;
+            client.reconnect(60).await
+        }).await.unwrap();
+
+        assert!(client.subscriptions.is_empty());
+        assert_eq!(gateway.count(UNSUBSCRIBE), 1);
+        // Not renewed by the clean reconnect
+        assert_eq!(gateway.count(SUBSCRIBE), 1);
+    }
+
+    #[tokio::test]
+    async fn publish_registers_again_when_gateway_forgot() {
+        let (socket, gateway) = link();
+        let mut gateway = TestGateway::new(gateway);
+        let mut client = client(socket);
+        let msg = MqttMessage::new("test/out", "1", Some(1), false).unwrap();
+
+        with_gateway(&mut gateway, async {
+            client.connect(60).await?;
+            client.publish(msg.clone()).await
+        }).await.unwrap();
+        gateway.forget_registrations();
+        with_gateway(&mut gateway, async {
+            client.reconnect(60).await?;
+            client.publish(msg.clone()).await
+        }).await.unwrap();
+
+        // The persistent reconnect trusted the gateway, the rejected publish
+        // then registered again and went out a second time
+        assert_eq!(gateway.count(REGISTER), 2);
+        assert_eq!(gateway.count(PUBLISH), 3);
+    }
+
+    #[tokio::test]
+    async fn register_before_suback() {
+        let (socket, mut gateway) = link();
+        let mut client = client(socket);
+
+        let (msg, ()) = join(
+            async {
+                client.subscribe("test/+", 0).await?;
+                client.next_message().await
+            },
+            async {
+                let Message::Subscribe(Subscribe { msg_id, .. }) = next_packet(&mut gateway).await else {
+                    panic!("expected SUBSCRIBE");
+                };
+                // The gateway registers a match of the filter before it acks
+                send_packet(&mut gateway, Message::Register(Register {
+                    topic_id: 7, msg_id: 100, topic_name: TopicName::from("test/a")
+                })).await;
+                assert!(matches!(
+                    next_packet(&mut gateway).await,
+                    Message::RegAck(RegAck { topic_id: 7, msg_id: 100, code: ReturnCode::Accepted })
+                ));
+                send_packet(&mut gateway, Message::SubAck(SubAck {
+                    flags: Flags::default(), msg_id, topic_id: 0, code: ReturnCode::Accepted
+                })).await;
+                send_packet(&mut gateway, publish_packet(7, "hello")).await;
+            }
+        ).await;
+
+        let msg = msg.unwrap();
+        assert_eq!(msg.topic, "test/a");
+        assert_eq!(msg.payload, "hello");
+    }
+
+    #[tokio::test]
+    async fn subscribe_refuses_more_subscriptions_than_it_tracks() {
+        let (socket, gateway) = link();
+        let mut gateway = TestGateway::new(gateway);
+        let mut client = client(socket);
+
+        let result = with_gateway(&mut gateway, async {
+            client.connect(60).await?;
+            for i in 0..16 {
+                client.subscribe(&format!("test/{}", i), 0).await?;
+            }
+            // Subscribing again to a tracked topic is still fine
+            client.subscribe("test/0", 1).await?;
+            Ok::<_, Error>(client.subscribe("test/16", 0).await)
+        }).await.unwrap();
+
+        assert!(matches!(result, Err(Error::TopicFailedInsert)));
+        assert_eq!(gateway.count(SUBSCRIBE), 17);
+    }
+
+    /// A client whose inbound topic table is full of `topic_type` topics
+    fn client_with_full_table(
+        socket: MemorySocket<'static>, topic_type: TopicIdType
+    ) -> MqttSnClient<MemorySocket<'static>> {
+        let mut client = client(socket);
+        for i in 0..Topics::CAPACITY {
+            let topic = String::try_from(format!("test/old/{}", i).as_str()).unwrap();
+            client.sub_topics.insert(topic, topic_type, 100 + i as u16).unwrap();
+        }
+        client
+    }
+
+    #[tokio::test]
+    async fn subscribe_evicts_from_a_full_topic_table() {
+        let (socket, gateway) = link();
+        let mut gateway = TestGateway::new(gateway);
+        let mut client = client_with_full_table(socket, TopicIdType::Id);
+
+        let (_, topic_id) = with_gateway(&mut gateway, async {
+            client.connect(60).await?;
+            client.subscribe("test/new", 0).await
+        }).await.unwrap();
+
+        assert_eq!(client.inbound_topics().count(), Topics::CAPACITY);
+        assert!(client.inbound_topics().any(|t| t == ("test/new", TopicIdType::Id, topic_id)));
+    }
+
+    #[tokio::test]
+    async fn subscribe_fails_when_no_topic_can_be_evicted() {
+        let (socket, gateway) = link();
+        let mut gateway = TestGateway::new(gateway);
+        // Predefined topics are never evicted
+        let mut client = client_with_full_table(socket, TopicIdType::PreDef);
+
+        let result = with_gateway(&mut gateway, async {
+            client.connect(60).await?;
+            Ok::<_, Error>(client.subscribe("test/new", 0).await)
+        }).await.unwrap();
+
+        assert!(matches!(result, Err(Error::TopicFailedInsert)));
+        assert_eq!(gateway.count(SUBSCRIBE), 0);
+        assert!(client.subscriptions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn normal_topic_subscribed_by_name() {
+        let (socket, mut gateway) = link();
+        let mut client = client(socket);
+
+        let (result, ()) = join(
+            async {
+                let granted = client.subscribe("test/in", 1).await?;
+                let msg = client.next_message().await?;
+                client.unsubscribe("test/in").await?;
+                Ok::<_, Error>((granted, msg))
+            },
+            async {
+                let Message::Subscribe(Subscribe { flags, msg_id, topic }) = next_packet(&mut gateway).await else {
+                    panic!("expected SUBSCRIBE without REGISTER");
+                };
+                assert_eq!(flags.topic_id_type(), 0);
+                assert!(matches!(topic, TopicNameOrId::Name(name) if name.as_str() == "test/in"));
+                let mut flags = Flags::default();
+                flags.set_qos(1);
+                send_packet(&mut gateway, Message::SubAck(SubAck {
+                    flags, msg_id, topic_id: 9, code: ReturnCode::Accepted
+                })).await;
+                send_packet(&mut gateway, publish_packet(9, "hello")).await;
+
+                let Message::Unsubscribe(Unsubscribe { flags, msg_id, topic }) = next_packet(&mut gateway).await else {
+                    panic!("expected UNSUBSCRIBE");
+                };
+                assert_eq!(flags.topic_id_type(), 0);
+                assert!(matches!(topic, TopicNameOrId::Name(name) if name.as_str() == "test/in"));
+                send_packet(&mut gateway, Message::UnsubAck(UnsubAck { msg_id })).await;
+            }
+        ).await;
+
+        let (granted, msg) = result.unwrap();
+        assert_eq!(granted, (1, 9));
+        assert_eq!(msg.topic, "test/in");
+        assert_eq!(client.inbound_topics().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn garbage_datagram_is_skipped() {
+        let (socket, mut gateway) = link();
+        let mut client = client(socket);
+
+        let (result, ()) = join(
+            client.ping(),
+            async {
+                assert!(matches!(next_packet(&mut gateway).await, Message::PingReq(_)));
+                // Not a message type
+                gateway.send(&[2, 0xFF]).await.unwrap();
+                send_packet(&mut gateway, Message::PingResp(PingResp {})).await;
+            }
+        ).await;
+
+        result.unwrap();
+        assert_eq!(client.metrics().parse_errors, 1);
+        assert_eq!(client.metrics().retransmissions, 0);
+    }
+
+    #[tokio::test]
+    async fn same_topic_id_for_publishing_and_receiving() {
+        let (socket, mut gateway) = link();
+        let mut client = client(socket);
+
+        let (msg, ()) = join(
+            async {
+                client.publish(MqttMessage::new("test/out", "1", Some(0), false)?).await?;
+                client.next_message().await
+            },
+            async {
+                let Message::Register(Register { msg_id, .. }) = next_packet(&mut gateway).await else {
+                    panic!("expected REGISTER");
+                };
+                send_packet(&mut gateway, Message::RegAck(RegAck {
+                    topic_id: 7, msg_id, code: ReturnCode::Accepted
+                })).await;
+                assert!(matches!(
+                    next_packet(&mut gateway).await,
+                    Message::Publish(Publish { topic_id: 7, .. })
+                ));
+                // The gateway's own numbering for inbound topics reuses 7
+                send_packet(&mut gateway, Message::Register(Register {
+                    topic_id: 7, msg_id: 100, topic_name: TopicName::from("test/in")
+                })).await;
+                assert!(matches!(next_packet(&mut gateway).await, Message::RegAck(_)));
+                send_packet(&mut gateway, publish_packet(7, "2")).await;
+            }
+        ).await;
+
+        assert_eq!(msg.unwrap().topic, "test/in");
+        assert!(client.topics().eq([("test/out", TopicIdType::Id, 7)]));
+        assert!(client.inbound_topics().eq([("test/in", TopicIdType::Id, 7)]));
+    }
+
+    #[tokio::test]
+    async fn publish_to_carries_retain() {
+        let (socket, mut gateway) = link();
+        let mut client = client(socket);
+
+        let (result, publish) = join(
+            async {
+                // A short topic, so nothing is registered first
+                let handle = client.topic_handle("ab").await?;
+                client.publish_to(&handle, "1", Some(0), true).await
+            },
+            next_raw(&mut gateway)
+        ).await;
+
+        result.unwrap();
+        // PUBLISH with the retain flag in bit 4
+        assert_eq!(publish[1], PUBLISH);
+        assert_eq!(publish[2] & 0x10, 0x10);
+    }
+
+    #[tokio::test]
+    async fn remapped_topic_invalidates_handles() {
+        let (socket, mut gateway) = link();
+        let mut client = client(socket);
+
+        let (handle, ()) = join(
+            async {
+                let handle = client.topic_handle("test/out").await?;
+                client.publish_to(&handle, "1", Some(1), false).await?;
+                Ok::<_, Error>(handle)
+            },
+            async {
+                let Message::Register(Register { msg_id, .. }) = next_packet(&mut gateway).await else {
+                    panic!("expected REGISTER");
+                };
+                send_packet(&mut gateway, Message::RegAck(RegAck {
+                    topic_id: 7, msg_id, code: ReturnCode::Accepted
+                })).await;
+                let Message::Publish(Publish { topic_id: 7, msg_id, .. }) = next_packet(&mut gateway).await else {
+                    panic!("expected PUBLISH on topic id 7");
+                };
+                // The gateway acks under another id for the same topic
+                send_packet(&mut gateway, Message::PubAck(PubAck {
+                    topic_id: 8, msg_id, code: ReturnCode::Accepted
+                })).await;
+            }
+        ).await;
+
+        let handle = handle.unwrap();
+        assert!(client.topics().eq([("test/out", TopicIdType::Id, 8)]));
+        assert!(matches!(
+            client.publish_to(&handle, "2", Some(0), false).await,
+            Err(Error::TopicNotRegistered)
+        ));
+    }
+
+    #[tokio::test]
+    async fn publish_to_more_topics_than_the_table_holds() {
+        let (socket, gateway) = link();
+        let mut gateway = TestGateway::new(gateway);
+        let mut client = client(socket);
+
+        with_gateway(&mut gateway, async {
+            client.connect(60).await?;
+            for i in 0..50 {
+                let topic = format!("test/{}", i);
+                client.publish(MqttMessage::new(&topic, "1", Some(1), false)?).await?;
+            }
+            Ok::<_, Error>(())
+        }).await.unwrap();
+
+        assert_eq!(gateway.count(REGISTER), 50);
+        assert_eq!(gateway.count(PUBLISH), 50);
+        assert_eq!(client.topics().count(), Topics::CAPACITY);
+        assert!(client.topics().any(|(topic, _, _)| topic == "test/49"));
+    }
+
+    #[tokio::test]
+    async fn retransmission_differs_only_in_dup() {
+        let (socket, mut gateway) = link();
+        let mut client = client(socket);
+        client.register_predefined("test/pre", 5).unwrap();
+
+        let (result, (first, second)) = join(
+            client.publish(MqttMessage::new("test/pre", "hello", Some(1), true).unwrap()),
+            async {
+                // Leave the first transmission unanswered
+                let first = next_raw(&mut gateway).await;
+                let second = next_raw(&mut gateway).await;
+                let Ok((Message::Publish(Publish { msg_id, .. }), _)) = Message::try_read(&second, ()) else {
+                    panic!("expected PUBLISH");
+                };
+                send_packet(&mut gateway, Message::PubAck(PubAck {
+                    topic_id: 5, msg_id, code: ReturnCode::Accepted
+                })).await;
+                (first, second)
+            }
+        ).await;
+
+        assert_eq!(result.unwrap(), Some(5));
+        assert_eq!(first[2] & 0x80, 0);
+        assert_eq!(second[2], first[2] | 0x80);
+        assert_eq!(first[..2], second[..2]);
+        assert_eq!(first[3..], second[3..]);
+    }
+
+    #[tokio::test]
+    async fn predefined_topic_is_not_registered() {
+        let (socket, gateway) = link();
+        let mut gateway = TestGateway::new(gateway);
+        let mut client = client(socket);
+        client.register_predefined("test/pre", 5).unwrap();
+
+        let topic_id = with_gateway(&mut gateway, async {
+            client.connect(60).await?;
+            client.publish(MqttMessage::new("test/pre", "1", Some(1), false)?).await
+        }).await.unwrap();
+
+        assert_eq!(topic_id, Some(5));
+        assert_eq!(gateway.count(REGISTER), 0);
+        assert_eq!(gateway.count(PUBLISH), 1);
+    }
+
+    #[tokio::test]
+    async fn unanswered_ping_is_sent_n_retry_times() {
+        let (socket, mut gateway) = link();
+        let mut client = client(socket);
+
+        assert!(matches!(client.ping().await, Err(Error::AckError)));
+
+        let mut sent = 0;
+        while let Ok(packet) = with_timeout(Duration::from_millis(50), next_packet(&mut gateway)).await {
+            assert!(matches!(packet, Message::PingReq(_)));
+            sent += 1;
+        }
+        assert_eq!(sent, client.n_retry);
+    }
+
+    #[tokio::test]
+    async fn ack_exhausted_handler_may_capture_state() {
+        let (socket, _gateway) = link();
+        let mut client = client(socket);
+        let exhausted = Rc::new(Cell::new(None));
+        let seen = exhausted.clone();
+        client.on_ack_exhausted(move |kind| seen.set(Some(kind)));
+
+        assert!(matches!(client.ping().await, Err(Error::AckError)));
+        assert_eq!(exhausted.get(), Some(AckKind::Ping));
+    }
+
+    #[tokio::test]
+    async fn tick_handler_may_capture_state() {
+        let (socket, _gateway) = link();
+        let mut client = client(socket);
+        let ticks = Rc::new(Cell::new(0));
+        let counter = ticks.clone();
+        client.on_tick(Duration::from_millis(10), move || counter.set(counter.get() + 1));
+
+        assert!(matches!(client.ping().await, Err(Error::AckError)));
+        assert!(ticks.get() >= client.n_retry);
+    }
+
+    #[tokio::test]
+    async fn disconnect_handler_may_capture_state() {
+        let (socket, mut gateway) = link();
+        let mut client = client(socket);
+        let reason = Rc::new(Cell::new(None));
+        let seen = reason.clone();
+        client.on_disconnect(move |reason| seen.set(Some(reason)));
+
+        let (result, ()) = join(
+            async {
+                client.connect(60).await?;
+                client.ping().await
+            },
+            async {
+                assert!(matches!(next_packet(&mut gateway).await, Message::Connect(_)));
+                send_packet(&mut gateway, Message::ConnAck(ConnAck {
+                    code: ReturnCode::Accepted
+                })).await;
+                assert!(matches!(next_packet(&mut gateway).await, Message::PingReq(_)));
+                send_packet(&mut gateway, Message::Disconnect(Disconnect { duration: None })).await;
+            }
+        ).await;
+
+        assert!(matches!(result, Err(Error::Disconnected)));
+        assert_eq!(reason.get(), Some(DisconnectReason::Disconnected));
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+    }
+
+    fn session() -> SessionState {
+        let mut state = SessionState {
+            msg_id: 41,
+            keep_alive: 120,
+            topics: heapless::Vec::new(),
+            sub_topics: heapless::Vec::new(),
+            subscriptions: heapless::Vec::new(),
+        };
+        state.topics.push((String::try_from("out").unwrap(), TopicIdType::Id, 1)).unwrap();
+        state.sub_topics.push((String::try_from("in").unwrap(), TopicIdType::Id, 2)).unwrap();
+        state.subscriptions.push((String::try_from("in").unwrap(), 1)).unwrap();
+        state.subscriptions.push((String::try_from("all/#").unwrap(), 0)).unwrap();
+        state
+    }
+
+    #[test]
+    fn restore_session_keeps_the_exported_state() {
+        let client = restore(session()).unwrap();
+        let exported = client.export_session();
+        let state = session();
+
+        assert_eq!(exported.msg_id, state.msg_id);
+        assert_eq!(exported.keep_alive, state.keep_alive);
+        assert_eq!(exported.topics, state.topics);
+        assert_eq!(exported.sub_topics, state.sub_topics);
+        assert_eq!(exported.subscriptions, state.subscriptions);
+    }
+
+    #[test]
+    fn restore_session_survives_the_codec() {
+        let mut buf = [0u8; 512];
+        let len = restore(session()).unwrap().export_session().try_write(&mut buf, ()).unwrap();
+        let (state, _) = SessionState::try_read(&buf[..len], ()).unwrap();
+
+        let client = restore(state).unwrap();
+        assert!(client.topics().eq([("out", TopicIdType::Id, 1)]));
+        assert!(client.inbound_topics().eq([("in", TopicIdType::Id, 2)]));
+    }
+
+    #[test]
+    fn restore_session_rejects_an_oversized_topic() {
+        let long: String<256> = core::iter::repeat('a').take(MAX_TOPIC_LEN + 1).collect();
+        let mut state = session();
+        state.topics.push((long.clone(), TopicIdType::Id, 3)).unwrap();
+        assert!(matches!(restore(state), Err(Error::InvalidSession)));
+
+        let mut state = session();
+        state.subscriptions.push((long, 0)).unwrap();
+        assert!(matches!(restore(state), Err(Error::InvalidSession)));
+    }
+
+    #[test]
+    fn restore_session_rejects_an_invalid_subscription() {
+        let mut state = session();
+        state.subscriptions.push((String::try_from("a/b#").unwrap(), 0)).unwrap();
+        assert!(matches!(restore(state), Err(Error::InvalidSession)));
+    }
+
+    #[test]
+    fn restore_session_rejects_a_duplicate_topic() {
+        let mut state = session();
+        state.topics.push((String::try_from("other").unwrap(), TopicIdType::Id, 1)).unwrap();
+        assert!(matches!(restore(state), Err(Error::InvalidSession)));
+    }
+
+    #[test]
+    fn builder_sets_the_delivery_strategy() {
+        let outbound: &'static Channel = Box::leak(Box::new(Channel::new()));
+        let inbound: &'static Channel = Box::leak(Box::new(Channel::new()));
+        let client = MqttSnClient::builder("test", link().0)
+            .rx(outbound.dyn_subscriber().unwrap())
+            .tx(inbound.dyn_publisher().unwrap())
+            .delivery_strategy(DeliveryStrategy::DropNew)
+            .build()
+            .unwrap();
+        assert_eq!(client.delivery, Some(DeliveryStrategy::DropNew));
+    }
+
+    #[test]
+    fn msg_id_wraps_past_zero() {
+        let mut msg_id = MsgId { last_id: u16::MAX - 1 };
+        assert_eq!(msg_id.next(), u16::MAX);
+        assert_eq!(msg_id.next(), 1);
+        assert_eq!(msg_id.next(), 2);
+    }
+
+    #[tokio::test]
+    async fn will_topic_flags_carry_qos_and_retain() {
+        let (socket, mut gateway) = link();
+        let mut client = client(socket);
+
+        let (result, will_topic) = join(
+            client.connect_with_will(60, "test/will", "gone", 1, true),
+            async {
+                assert!(matches!(next_packet(&mut gateway).await, Message::Connect(_)));
+                send_packet(&mut gateway, Message::WillTopicReq(WillTopicReq {})).await;
+                let will_topic = next_raw(&mut gateway).await;
+                send_packet(&mut gateway, Message::WillMsgReq(WillMsgReq {})).await;
+                assert!(matches!(next_packet(&mut gateway).await, Message::WillMsg(_)));
+                send_packet(&mut gateway, Message::ConnAck(ConnAck {
+                    code: ReturnCode::Accepted
+                })).await;
+                will_topic
+            }
+        ).await;
+
+        result.unwrap();
+        // WILLTOPIC, then QoS 1 in bits 5-6 and retain in bit 4
+        assert_eq!(will_topic[1], 0x07);
+        assert_eq!(will_topic[2] & 0x70, 0x30);
+    }
+
+    #[tokio::test]
+    async fn over_long_topic_fails_before_sending() {
+        let (socket, mut gateway) = link();
+        let mut client = client(socket);
+        let topic = "t".repeat(MAX_TOPIC_LEN + 1);
+
+        let msg = MqttMessage::new(&topic, "1", Some(1), false).unwrap();
+        assert!(matches!(client.publish(msg).await, Err(Error::MessageTooLarge)));
+        assert!(matches!(client.subscribe(&topic, 0).await, Err(Error::MessageTooLarge)));
+
+        let mut buffer = [0u8; 512];
+        assert!(with_timeout(Duration::from_millis(50), gateway.recv(&mut buffer)).await.is_err());
+    }
+
+    /// A transport whose next receive fails once `fail_recv` is set, counting
+    /// the reconnects that follow
+    struct FlakySocket {
+        inner: MemorySocket<'static>,
+        fail_recv: bool,
+        reconnects: u8,
+    }
+
+    impl SendBytes for FlakySocket {
+        async fn send(&mut self, buf: &[u8]) -> Result<(), SocketError> {
+            self.inner.send(buf).await
+        }
+    }
+
+    impl ReceiveBytes for FlakySocket {
+        async fn recv<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], SocketError> {
+            if core::mem::take(&mut self.fail_recv) {
+                return Err(SocketError::Generic);
+            }
+            self.inner.recv(buf).await
+        }
+
+        async fn recv_from<'a>(
+            &mut self, buf: &'a mut [u8]
+        ) -> Result<(&'a mut [u8], SocketAddr), SocketError> {
+            if core::mem::take(&mut self.fail_recv) {
+                return Err(SocketError::Generic);
+            }
+            self.inner.recv_from(buf).await
+        }
+    }
+
+    impl ConnectTo for FlakySocket {
+        async fn connect(&mut self, _addr: SocketAddr) -> Result<(), SocketError> {
+            Ok(())
+        }
+    }
+
+    impl Reconnect for FlakySocket {
+        async fn reconnect(&mut self) -> Result<(), SocketError> {
+            self.reconnects += 1;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn run_recovers_from_a_failed_receive() {
+        let (socket, gateway) = link();
+        let mut gateway = TestGateway::new(gateway);
+        let mut client = client(FlakySocket { inner: socket, fail_recv: false, reconnects: 0 });
+        // Without a keep-alive or sleep interval run pings continuously
+        with_gateway(&mut gateway, client.connect(0)).await.unwrap();
+
+        client.socket.fail_recv = true;
+        with_gateway(
+            &mut gateway,
+            select(client.run(0), Timer::after(Duration::from_millis(200)))
+        ).await;
+
+        // The failed wait for a PINGRESP made run reconnect instead of
+        // retrying the ping on a broken transport
+        assert_eq!(client.socket.reconnects, 1);
+        assert_eq!(client.state(), ConnectionState::Connected);
+        assert_eq!(gateway.count(CONNECT), 2);
+    }
+}
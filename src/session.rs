@@ -0,0 +1,219 @@
+use heapless::{String, Vec};
+use byte::{BytesExt, TryRead, TryWrite};
+use byte::ctx::{Str, BE};
+use crate::mqttsn::TopicIdType;
+
+pub type TopicEntry = (String<256>, TopicIdType, u16);
+
+/// Client state needed to resume a persistent session after a restart
+/// without registering and subscribing again. It is written and read with
+/// `TryWrite`/`TryRead` so it can be kept in flash or a file.
+#[derive(Debug, Clone)]
+pub struct SessionState {
+    /// Last msg_id used
+    pub msg_id: u16,
+    pub keep_alive: u16,
+    /// Topics registered for publishing
+    pub topics: Vec<TopicEntry, 16>,
+    /// Topic ids assigned for receiving
+    pub sub_topics: Vec<TopicEntry, 16>,
+    /// Subscribed topics with the requested QoS
+    pub subscriptions: Vec<(String<256>, u8), 16>,
+}
+
+impl TryWrite for SessionState {
+    fn try_write(self, bytes: &mut [u8], _ctx: ()) -> byte::Result<usize> {
+        let offset = &mut 0;
+        bytes.write_with::<u16>(offset, self.msg_id, BE)?;
+        bytes.write_with::<u16>(offset, self.keep_alive, BE)?;
+        for table in [&self.topics, &self.sub_topics] {
+            bytes.write::<u8>(offset, table.len() as u8)?;
+            for (topic, topic_type, id) in table.iter() {
+                bytes.write::<u8>(offset, *topic_type as u8)?;
+                bytes.write_with::<u16>(offset, *id, BE)?;
+                write_str(bytes, offset, topic)?;
+            }
+        }
+        bytes.write::<u8>(offset, self.subscriptions.len() as u8)?;
+        for (topic, qos) in self.subscriptions.iter() {
+            bytes.write::<u8>(offset, *qos)?;
+            write_str(bytes, offset, topic)?;
+        }
+        Ok(*offset)
+    }
+}
+
+impl<'a> TryRead<'a> for SessionState {
+    fn try_read(bytes: &'a [u8], _ctx: ()) -> byte::Result<(Self, usize)> {
+        let offset = &mut 0;
+        let msg_id = bytes.read_with::<u16>(offset, BE)?;
+        let keep_alive = bytes.read_with::<u16>(offset, BE)?;
+        let mut tables: [Vec<TopicEntry, 16>; 2] = [Vec::new(), Vec::new()];
+        for table in tables.iter_mut() {
+            for _ in 0..bytes.read::<u8>(offset)? {
+                let topic_type = TopicIdType::try_from(bytes.read::<u8>(offset)?)
+                    .map_err(|_| bad_input("invalid topic id type"))?;
+                let id = bytes.read_with::<u16>(offset, BE)?;
+                let topic = read_str(bytes, offset)?;
+                table.push((topic, topic_type, id)).map_err(|_| bad_input("too many topics"))?;
+            }
+        }
+        let mut subscriptions = Vec::new();
+        for _ in 0..bytes.read::<u8>(offset)? {
+            let qos = bytes.read::<u8>(offset)?;
+            subscriptions.push((read_str(bytes, offset)?, qos))
+                .map_err(|_| bad_input("too many subscriptions"))?;
+        }
+        let [topics, sub_topics] = tables;
+        Ok((SessionState { msg_id, keep_alive, topics, sub_topics, subscriptions }, *offset))
+    }
+}
+
+fn write_str(bytes: &mut [u8], offset: &mut usize, s: &str) -> byte::Result<()> {
+    bytes.write_with::<u16>(offset, s.len() as u16, BE)?;
+    bytes.write::<&[u8]>(offset, s.as_bytes())
+}
+
+fn read_str(bytes: &[u8], offset: &mut usize) -> byte::Result<String<256>> {
+    let len = bytes.read_with::<u16>(offset, BE)?;
+    let s = bytes.read_with::<&str>(offset, Str::Len(len.into()))?;
+    String::try_from(s).map_err(|_| bad_input("topic too long"))
+}
+
+fn bad_input(err: &'static str) -> byte::Error {
+    byte::Error::BadInput { err }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(topic: &str, topic_type: TopicIdType, id: u16) -> TopicEntry {
+        (String::try_from(topic).unwrap(), topic_type, id)
+    }
+
+    fn state() -> SessionState {
+        let mut state = SessionState {
+            msg_id: 513,
+            keep_alive: 60,
+            topics: Vec::new(),
+            sub_topics: Vec::new(),
+            subscriptions: Vec::new(),
+        };
+        state.topics.push(entry("a/b", TopicIdType::Id, 1)).unwrap();
+        state.topics.push(entry("pre", TopicIdType::PreDef, 7)).unwrap();
+        state.sub_topics.push(entry("c", TopicIdType::Id, 300)).unwrap();
+        state.sub_topics.push(entry("sh", TopicIdType::Short, 0x7368)).unwrap();
+        state.subscriptions.push((String::try_from("c").unwrap(), 1)).unwrap();
+        state.subscriptions.push((String::try_from("d/+/#").unwrap(), 0)).unwrap();
+        state
+    }
+
+    /// msg_id and keep_alive of an encoded state
+    fn header(bytes: &mut [u8], offset: &mut usize) {
+        bytes.write_with::<u16>(offset, 1, BE).unwrap();
+        bytes.write_with::<u16>(offset, 60, BE).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buf = [0u8; 512];
+        let len = state().try_write(&mut buf, ()).unwrap();
+        let (read, read_len) = SessionState::try_read(&buf[..len], ()).unwrap();
+
+        let state = state();
+        assert_eq!(read_len, len);
+        assert_eq!(read.msg_id, state.msg_id);
+        assert_eq!(read.keep_alive, state.keep_alive);
+        assert_eq!(read.topics, state.topics);
+        assert_eq!(read.sub_topics, state.sub_topics);
+        assert_eq!(read.subscriptions, state.subscriptions);
+    }
+
+    #[test]
+    fn truncated_input_fails() {
+        let mut buf = [0u8; 512];
+        let len = state().try_write(&mut buf, ()).unwrap();
+        for end in 0..len {
+            assert!(SessionState::try_read(&buf[..end], ()).is_err(), "read {} of {} bytes", end, len);
+        }
+    }
+
+    #[test]
+    fn write_fails_when_the_buffer_is_short() {
+        let mut buf = [0u8; 16];
+        assert!(state().try_write(&mut buf, ()).is_err());
+    }
+
+    #[test]
+    fn oversized_topic_fails() {
+        let long: String<300> = core::iter::repeat('a').take(257).collect();
+        let mut buf = [0u8; 512];
+        let offset = &mut 0;
+        header(&mut buf, offset);
+        buf.write::<u8>(offset, 1).unwrap();
+        buf.write::<u8>(offset, TopicIdType::Id as u8).unwrap();
+        buf.write_with::<u16>(offset, 1, BE).unwrap();
+        write_str(&mut buf, offset, &long).unwrap();
+        buf.write::<u8>(offset, 0).unwrap();
+        buf.write::<u8>(offset, 0).unwrap();
+
+        assert!(matches!(
+            SessionState::try_read(&buf[..*offset], ()),
+            Err(byte::Error::BadInput { .. })
+        ));
+    }
+
+    #[test]
+    fn invalid_topic_id_type_fails() {
+        let mut buf = [0u8; 32];
+        let offset = &mut 0;
+        header(&mut buf, offset);
+        buf.write::<u8>(offset, 1).unwrap();
+        buf.write::<u8>(offset, 3).unwrap();
+        buf.write_with::<u16>(offset, 1, BE).unwrap();
+        write_str(&mut buf, offset, "a").unwrap();
+        buf.write::<u8>(offset, 0).unwrap();
+        buf.write::<u8>(offset, 0).unwrap();
+
+        assert!(matches!(
+            SessionState::try_read(&buf[..*offset], ()),
+            Err(byte::Error::BadInput { .. })
+        ));
+    }
+
+    #[test]
+    fn too_many_topics_fail() {
+        let mut buf = [0u8; 512];
+        let offset = &mut 0;
+        header(&mut buf, offset);
+        buf.write::<u8>(offset, 17).unwrap();
+        for id in 1..=17 {
+            buf.write::<u8>(offset, TopicIdType::Id as u8).unwrap();
+            buf.write_with::<u16>(offset, id, BE).unwrap();
+            write_str(&mut buf, offset, "a").unwrap();
+        }
+        buf.write::<u8>(offset, 0).unwrap();
+        buf.write::<u8>(offset, 0).unwrap();
+
+        assert!(matches!(
+            SessionState::try_read(&buf[..*offset], ()),
+            Err(byte::Error::BadInput { .. })
+        ));
+    }
+
+    #[test]
+    fn topic_that_is_not_utf8_fails() {
+        let mut buf = [0u8; 32];
+        let offset = &mut 0;
+        header(&mut buf, offset);
+        buf.write::<u8>(offset, 0).unwrap();
+        buf.write::<u8>(offset, 0).unwrap();
+        buf.write::<u8>(offset, 1).unwrap();
+        buf.write::<u8>(offset, 0).unwrap();
+        buf.write_with::<u16>(offset, 2, BE).unwrap();
+        buf.write::<&[u8]>(offset, &[0xC3, 0x28]).unwrap();
+
+        assert!(SessionState::try_read(&buf[..*offset], ()).is_err());
+    }
+}
@@ -1,9 +1,12 @@
 #[cfg(feature = "std")]
 use tokio::net::UdpSocket;
+use no_std_net::SocketAddr;
 
 #[derive(Debug)]
 pub enum SocketError {
     Generic,
+    /// The secure session couldn't be established with the peer
+    Handshake,
 }
 
 #[cfg(feature = "std")]
@@ -19,12 +22,60 @@ pub trait SendBytes {
 
 pub trait ReceiveBytes {
     async fn recv<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], SocketError>;
+
+    /// Receive a datagram along with its source address. Connected
+    /// transports report their fixed peer.
+    async fn recv_from<'a>(
+        &mut self, buf: &'a mut [u8]
+    ) -> Result<(&'a mut [u8], SocketAddr), SocketError>;
+}
+
+/// Transports that can be pointed at a different peer
+pub trait Connect {
+    async fn connect(&mut self, addr: SocketAddr) -> Result<(), SocketError>;
+}
+
+/// Transports that can recover after failing, e.g. by redoing a handshake
+/// with the same peer
+pub trait Reconnect {
+    async fn reconnect(&mut self) -> Result<(), SocketError>;
+}
+
+/// Supplies candidate gateway addresses, tried in order when connecting
+pub trait GatewayResolver {
+    /// The `index`th candidate, or `None` when there are no more
+    fn resolve(&mut self, index: usize) -> Option<SocketAddr>;
 }
 
+impl GatewayResolver for [SocketAddr] {
+    fn resolve(&mut self, index: usize) -> Option<SocketAddr> {
+        self.get(index).copied()
+    }
+}
 
 #[cfg(feature = "std")]
 pub struct TokioUdp(pub UdpSocket);
 
+#[cfg(feature = "std")]
+impl TokioUdp {
+    /// Bind to `local`, use `[::]:0` to pick any port on an IPv6 interface
+    pub async fn bind(local: SocketAddr) -> std::io::Result<Self> {
+        UdpSocket::bind(local).await
+            .map(TokioUdp)
+            .map_err(|e| std::io::Error::new(
+                e.kind(),
+                format!("failed to bind UDP socket to {}: {}", local, e),
+            ))
+    }
+
+    /// Associate the socket with `remote`, after which `send` goes to and
+    /// `recv` only accepts datagrams from that peer
+    pub async fn connect(&mut self, remote: SocketAddr) -> Result<(), SocketError> {
+        self.0.connect(remote).await?;
+        Ok(())
+    }
+}
+
 #[cfg(feature = "std")]
 impl SendBytes for TokioUdp {
     async fn send(&mut self, buf: &[u8]) -> Result<(), SocketError> {
@@ -38,4 +89,30 @@ impl ReceiveBytes for TokioUdp {
     async fn recv<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], SocketError> {
         Ok(self.0.recv(buf).await.map(|len| &mut buf[..len])?)
     }
+
+    async fn recv_from<'a>(
+        &mut self, buf: &'a mut [u8]
+    ) -> Result<(&'a mut [u8], SocketAddr), SocketError> {
+        let (len, addr) = self.0.recv_from(buf).await?;
+        Ok((&mut buf[..len], addr))
+    }
+}
+
+#[cfg(feature = "std")]
+impl Connect for TokioUdp {
+    async fn connect(&mut self, addr: SocketAddr) -> Result<(), SocketError> {
+        TokioUdp::connect(self, addr).await
+    }
+}
+
+#[cfg(feature = "std")]
+impl Reconnect for TokioUdp {
+    /// Associate again with the connected peer, clearing errors such as
+    /// ICMP port unreachable left on the socket
+    async fn reconnect(&mut self) -> Result<(), SocketError> {
+        if let Ok(peer) = self.0.peer_addr() {
+            self.0.connect(peer).await?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,278 @@
+//! In-process gateway and transport for loopback testing, no network needed.
+use heapless::{String, Vec, FnvIndexMap};
+use embassy_sync::channel::Channel;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use no_std_net::{SocketAddr, SocketAddrV4, Ipv4Addr};
+use mqtt_sn::defs::*;
+use byte::{TryRead, TryWrite};
+use crate::socket::{SendBytes, ReceiveBytes, Connect, Reconnect, SocketError};
+
+type Packet = Vec<u8, 512>;
+type Pipe = Channel<CriticalSectionRawMutex, Packet, 8>;
+
+/// A pair of in-memory pipes connecting two `MemorySocket`s
+pub struct MemoryLink {
+    a_to_b: Pipe,
+    b_to_a: Pipe,
+}
+
+impl MemoryLink {
+    pub const fn new() -> Self {
+        Self { a_to_b: Pipe::new(), b_to_a: Pipe::new() }
+    }
+
+    /// The two ends of the link, e.g. one for the client and one for the gateway
+    pub fn split(&self) -> (MemorySocket<'_>, MemorySocket<'_>) {
+        (
+            MemorySocket { tx: &self.a_to_b, rx: &self.b_to_a },
+            MemorySocket { tx: &self.b_to_a, rx: &self.a_to_b },
+        )
+    }
+}
+
+pub struct MemorySocket<'a> {
+    tx: &'a Pipe,
+    rx: &'a Pipe,
+}
+
+impl SendBytes for MemorySocket<'_> {
+    async fn send(&mut self, buf: &[u8]) -> Result<(), SocketError> {
+        let packet = Packet::from_slice(buf).map_err(|_| SocketError::Generic)?;
+        self.tx.send(packet).await;
+        Ok(())
+    }
+}
+
+impl ReceiveBytes for MemorySocket<'_> {
+    async fn recv<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], SocketError> {
+        let packet = self.rx.receive().await;
+        let buf = buf.get_mut(..packet.len()).ok_or(SocketError::Generic)?;
+        buf.copy_from_slice(&packet);
+        Ok(buf)
+    }
+
+    async fn recv_from<'a>(
+        &mut self, buf: &'a mut [u8]
+    ) -> Result<(&'a mut [u8], SocketAddr), SocketError> {
+        let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+        Ok((self.recv(buf).await?, addr))
+    }
+}
+
+impl Connect for MemorySocket<'_> {
+    // The other end of the link is the only peer
+    async fn connect(&mut self, _addr: SocketAddr) -> Result<(), SocketError> {
+        Ok(())
+    }
+}
+
+impl Reconnect for MemorySocket<'_> {
+    async fn reconnect(&mut self) -> Result<(), SocketError> {
+        Ok(())
+    }
+}
+
+/// Gateway side of the core flows for a single client: connect, ping,
+/// register, subscribe and publish. Publishes on a subscribed topic are
+/// echoed back to the client at QoS 0. A clean session connect drops the
+/// registrations and subscriptions, a persistent one keeps them.
+pub struct TestGateway<'a> {
+    socket: MemorySocket<'a>,
+    topics: FnvIndexMap<String<256>, u16, 64>,
+    subscriptions: Vec<u16, 16>,
+    /// Subscribed wildcard filters, which are accepted but not matched
+    filters: Vec<String<256>, 8>,
+    /// Message type of each packet received, oldest first
+    received: Vec<u8, 128>,
+    will_accepted: bool,
+    buffer: [u8; 512],
+}
+
+impl<'a> TestGateway<'a> {
+    pub fn new(socket: MemorySocket<'a>) -> Self {
+        Self {
+            socket,
+            topics: FnvIndexMap::new(),
+            subscriptions: Vec::new(),
+            filters: Vec::new(),
+            received: Vec::new(),
+            will_accepted: true,
+            buffer: [0u8; 512],
+        }
+    }
+
+    /// Serve the client forever
+    pub async fn run(&mut self) {
+        loop {
+            let _ = self.step().await;
+        }
+    }
+
+    /// Forget the topics registered so far, as a gateway that lost its state
+    /// would. Publishes on their ids are then rejected with InvalidTopicId.
+    pub fn forget_registrations(&mut self) {
+        self.topics.clear();
+    }
+
+    /// Whether will topic and message updates are accepted, the default,
+    /// or rejected as not supported
+    pub fn set_will_accepted(&mut self, accepted: bool) {
+        self.will_accepted = accepted;
+    }
+
+    /// Message types of the packets received so far, e.g. `0x0A` for each
+    /// REGISTER. Undecodable packets are left out, as are any beyond the
+    /// first 128.
+    pub fn received(&self) -> &[u8] {
+        &self.received
+    }
+
+    /// How many packets of message type `msg_type` were received
+    pub fn count(&self, msg_type: u8) -> usize {
+        self.received.iter().filter(|t| **t == msg_type).count()
+    }
+
+    /// Handle a single packet from the client
+    pub async fn step(&mut self) -> Result<(), SocketError> {
+        let bytes = self.socket.recv(&mut self.buffer).await?;
+        let msg = match Message::try_read(bytes, ()) {
+            Ok((msg, _)) => msg,
+            Err(_) => return Ok(()),
+        };
+        // A length of 1 announces the three byte length field
+        let msg_type = if bytes[0] == 1 { bytes[3] } else { bytes[1] };
+        let _ = self.received.push(msg_type);
+        match msg {
+            Message::Connect(Connect { flags, .. }) => {
+                if flags.clean_session() {
+                    self.topics.clear();
+                    self.subscriptions.clear();
+                    self.filters.clear();
+                }
+                self.send(Message::ConnAck(ConnAck { code: ReturnCode::Accepted })).await
+            },
+            Message::PingReq(_) => self.send(Message::PingResp(PingResp {})).await,
+            Message::Register(Register { msg_id, topic_name, .. }) => {
+                let (topic_id, code) = match self.topic_id(topic_name.as_str()) {
+                    Some(topic_id) => (topic_id, ReturnCode::Accepted),
+                    None => (0, ReturnCode::Rejected(RejectedReason::Congestion)),
+                };
+                self.send(Message::RegAck(RegAck { topic_id, msg_id, code })).await
+            },
+            // Topic id type 0 carries a topic name or filter, 1 a predefined
+            // topic id and 2 a short topic name, anything else is malformed
+            Message::Subscribe(Subscribe { flags, msg_id, topic }) => {
+                let congestion = ReturnCode::Rejected(RejectedReason::Congestion);
+                let (topic_id, code) = match (flags.topic_id_type(), topic) {
+                    (0, TopicNameOrId::Name(name)) if is_filter(name.as_str()) => {
+                        if self.add_filter(name.as_str()) {
+                            (0, ReturnCode::Accepted)
+                        } else {
+                            (0, congestion)
+                        }
+                    },
+                    (0, TopicNameOrId::Name(name)) => match self.topic_id(name.as_str()) {
+                        Some(topic_id) if self.subscribe(topic_id) => (topic_id, ReturnCode::Accepted),
+                        _ => (0, congestion),
+                    },
+                    (1 | 2, TopicNameOrId::Id(topic_id)) if self.subscribe(topic_id) => {
+                        (topic_id, ReturnCode::Accepted)
+                    },
+                    (1 | 2, TopicNameOrId::Id(_)) => (0, congestion),
+                    _ => (0, ReturnCode::Rejected(RejectedReason::NotSupported)),
+                };
+                self.send(Message::SubAck(SubAck { flags, msg_id, code, topic_id })).await
+            },
+            Message::Unsubscribe(Unsubscribe { flags, msg_id, topic }) => {
+                match (flags.topic_id_type(), topic) {
+                    (0, TopicNameOrId::Name(name)) => {
+                        self.filters.retain(|f| f.as_str() != name.as_str());
+                        let topic_id = String::try_from(name.as_str()).ok()
+                            .and_then(|name| self.topics.get(&name).copied());
+                        if let Some(topic_id) = topic_id {
+                            self.subscriptions.retain(|id| *id != topic_id);
+                        }
+                    },
+                    (1 | 2, TopicNameOrId::Id(topic_id)) => {
+                        self.subscriptions.retain(|id| *id != topic_id);
+                    },
+                    _ => (),
+                }
+                self.send(Message::UnsubAck(UnsubAck { msg_id })).await
+            },
+            Message::Publish(Publish { flags, topic_id, msg_id, data }) => {
+                // Normal topic ids must have been registered, predefined and
+                // short ones are taken as they are
+                let known = flags.topic_id_type() != 0
+                    || self.topics.values().any(|id| *id == topic_id);
+                if flags.qos() > 0 {
+                    let code = if known {
+                        ReturnCode::Accepted
+                    } else {
+                        ReturnCode::Rejected(RejectedReason::InvalidTopicId)
+                    };
+                    self.send(Message::PubAck(PubAck { topic_id, msg_id, code })).await?;
+                }
+                if self.subscriptions.contains(&topic_id) {
+                    let mut echo_flags = Flags::default();
+                    echo_flags.set_topic_id_type(flags.topic_id_type());
+                    self.send(Message::Publish(Publish {
+                        flags: echo_flags, topic_id, msg_id: 0, data
+                    })).await?;
+                }
+                Ok(())
+            },
+            Message::WillTopicUpd(_) => {
+                let code = self.will_code();
+                self.send(Message::WillTopicResp(WillTopicResp { code })).await
+            },
+            Message::WillMsgUpd(_) => {
+                let code = self.will_code();
+                self.send(Message::WillMsgResp(WillMsgResp { code })).await
+            },
+            Message::Disconnect(_) => {
+                self.send(Message::Disconnect(Disconnect { duration: None })).await
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Id of a topic, registering it on first use
+    fn topic_id(&mut self, name: &str) -> Option<u16> {
+        let name = String::try_from(name).ok()?;
+        if let Some(id) = self.topics.get(&name) {
+            return Some(*id);
+        }
+        let id = self.topics.len() as u16 + 1;
+        self.topics.insert(name, id).ok()?;
+        Some(id)
+    }
+
+    fn will_code(&self) -> ReturnCode {
+        if self.will_accepted {
+            ReturnCode::Accepted
+        } else {
+            ReturnCode::Rejected(RejectedReason::NotSupported)
+        }
+    }
+
+    fn subscribe(&mut self, topic_id: u16) -> bool {
+        self.subscriptions.contains(&topic_id) || self.subscriptions.push(topic_id).is_ok()
+    }
+
+    fn add_filter(&mut self, filter: &str) -> bool {
+        let Ok(filter) = String::try_from(filter) else {
+            return false;
+        };
+        self.filters.contains(&filter) || self.filters.push(filter).is_ok()
+    }
+
+    async fn send(&mut self, msg: Message) -> Result<(), SocketError> {
+        let len = msg.try_write(&mut self.buffer, ()).map_err(|_| SocketError::Generic)?;
+        self.socket.send(&self.buffer[..len]).await
+    }
+}
+
+fn is_filter(topic: &str) -> bool {
+    topic.contains(|c| c == '+' || c == '#')
+}
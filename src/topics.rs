@@ -1,42 +1,367 @@
-use heapless::{String, FnvIndexMap};
+use heapless::{String, Vec, FnvIndexMap};
 use crate::mqttsn::{MqttSnClientError, TopicIdType};
 
 type Error = MqttSnClientError;
 
+/// Topic name to id mappings. The table holds at most `CAPACITY` topics,
+/// inserting into a full table fails with `TopicTableFull`.
 pub struct Topics {
-    store: FnvIndexMap<String<256>, (TopicIdType, u16), 16>
+    store: FnvIndexMap<String<256>, (TopicIdType, u16), 16>,
+    generation: u16,
 }
 
 impl Topics {
+    pub const CAPACITY: usize = 16;
+
     pub fn new() -> Self {
         Self {
-            store: FnvIndexMap::<String<256>, (TopicIdType, u16), 16>::new()
+            store: FnvIndexMap::<String<256>, (TopicIdType, u16), 16>::new(),
+            generation: 0,
         }
     }
+    /// Bumped whenever registered ids are dropped, invalidating handles
+    pub fn generation(&self) -> u16 {
+        self.generation
+    }
     pub fn insert(
         &mut self,
         topic: String<256>,
         topic_type: TopicIdType,
         id: u16
     ) -> Result<(), Error> {
-        match self.get_by_id(id) {
+        match self.get_by_id(topic_type, id) {
             Ok(topic) => {self.store.remove(&String::try_from(topic)?);},
             _ => ()
         }
-        self.store.insert(topic, (topic_type, id)).map_err(|_|Error::TopicFailedInsert)?;
+        self.store.insert(topic, (topic_type, id)).map_err(|_| Error::TopicTableFull)?;
+        Ok(())
+    }
+    /// Insert, evicting a registered topic if the table is full. Topics for
+    /// which `keep` returns true are never evicted.
+    pub fn insert_evicting<F>(
+        &mut self,
+        topic: String<256>,
+        topic_type: TopicIdType,
+        id: u16,
+        keep: F
+    ) -> Result<(), Error>
+    where
+        F: Fn(&str) -> bool
+    {
+        if self.store.len() == self.store.capacity() && !self.store.contains_key(&topic) {
+            let evict = self.store.iter()
+                .find(|(t, (tt, _))| *tt == TopicIdType::Id && !keep(t))
+                .map(|(t, _)| t.clone());
+            if let Some(evict) = evict {
+                self.store.remove(&evict);
+                self.generation = self.generation.wrapping_add(1);
+            }
+        }
+        self.insert(topic, topic_type, id)
+    }
+    /// Whether `insert_evicting` can take `topic`, into free space or by
+    /// evicting a registered topic that `keep` doesn't protect
+    pub fn has_room<F>(&self, topic: &str, keep: F) -> bool
+    where
+        F: Fn(&str) -> bool
+    {
+        self.store.len() < self.store.capacity()
+            || self.get_by_topic(topic).is_some()
+            || self.store.iter().any(|(t, (tt, _))| *tt == TopicIdType::Id && !keep(t))
+    }
+    /// Move the topic known under `old_id` to `new_id`. Handles still carry
+    /// `old_id`, so this invalidates them like dropping it would.
+    pub fn remap(&mut self, topic_type: TopicIdType, old_id: u16, new_id: u16) -> Result<(), Error> {
+        let topic = String::try_from(self.get_by_id(topic_type, old_id)?)?;
+        self.insert(topic, topic_type, new_id)?;
+        self.generation = self.generation.wrapping_add(1);
         Ok(())
     }
+    /// Drop all gateway-registered topics, keeping predefined and short ones
+    pub fn clear_registered(&mut self) {
+        let registered: Vec<String<256>, 16> = self.store.iter()
+            .filter(|(_, (topic_type, _))| *topic_type == TopicIdType::Id)
+            .map(|(topic, _)| topic.clone())
+            .collect();
+        for topic in registered.iter() {
+            self.store.remove(topic);
+        }
+        self.generation = self.generation.wrapping_add(1);
+    }
+    /// Forget a topic, returning its id type and id if it was known
+    pub fn remove(&mut self, topic: &str) -> Option<(TopicIdType, u16)> {
+        let removed = self.store.remove(&String::try_from(topic).ok()?)?;
+        self.generation = self.generation.wrapping_add(1);
+        Some(removed)
+    }
+    /// Each topic with its id type and id
+    pub fn iter(&self) -> impl Iterator<Item = (&str, TopicIdType, u16)> {
+        self.store.iter().map(|(topic, (topic_type, id))| (topic.as_str(), *topic_type, *id))
+    }
     pub fn get_by_topic(&self, topic: &str) -> Option<&(TopicIdType, u16)> {
         match String::try_from(topic) {
             Ok(topic) => self.store.get(&topic),
             _ => None
         }
     }
-    pub fn get_by_id(&self, id: u16) -> Result<&str, Error> {
-        if let Some((topic, _)) = self.store.iter().filter(|(_, (_, i))| i.clone() == id).next() {
+    /// Look up a topic by id within its id space. Normal and predefined ids
+    /// share the numeric range, so the type is needed to tell them apart.
+    pub fn get_by_id(&self, topic_type: TopicIdType, id: u16) -> Result<&str, Error> {
+        if let Some((topic, _)) = self.store.iter()
+            .filter(|(_, (t, i))| *t == topic_type && *i == id)
+            .next()
+        {
             return Ok(topic);
         } else {
             return Err(Error::TopicNotRegistered);
         }
     }
-}
\ No newline at end of file
+}
+
+/// Longest topic name that fits a REGISTER with a one byte length field,
+/// which is what every gateway accepts
+pub const MAX_TOPIC_LEN: usize = 249;
+
+/// Check that a topic name or filter fits a REGISTER or SUBSCRIBE packet
+pub fn validate_topic_len(topic: &str) -> Result<(), Error> {
+    if topic.len() > MAX_TOPIC_LEN {
+        return Err(Error::MessageTooLarge);
+    }
+    Ok(())
+}
+
+/// Check a subscription filter against the wildcard rules: `+` must occupy a
+/// whole level and `#` must be a whole level and the last one.
+pub fn validate_filter(filter: &str) -> Result<(), Error> {
+    if filter.is_empty() {
+        return Err(Error::InvalidFilter);
+    }
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        let valid = match level {
+            "#" => levels.peek().is_none(),
+            "+" => true,
+            _ => !level.contains(|c| c == '+' || c == '#'),
+        };
+        if !valid {
+            return Err(Error::InvalidFilter);
+        }
+    }
+    Ok(())
+}
+
+/// Whether `topic` matches the subscription `filter`, with `+` matching one
+/// level and `#` all remaining levels. As in MQTT, a filter starting with a
+/// wildcard doesn't match topics starting with `$`.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && filter.starts_with(|c| c == '+' || c == '#') {
+        return false;
+    }
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => (),
+            (level, Some(topic_level)) if level == topic_level => (),
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+/// Id of a short topic, a topic name of exactly two ASCII characters sent
+/// in place of the topic id
+pub fn short_topic_id(topic: &str) -> Option<u16> {
+    match topic.as_bytes() {
+        [a, b] if a.is_ascii() && b.is_ascii() => Some(u16::from_be_bytes([*a, *b])),
+        _ => None
+    }
+}
+
+/// Name of the short topic sent as `id`
+pub fn short_topic_name(id: u16) -> Result<String<256>, Error> {
+    let bytes = id.to_be_bytes();
+    if !bytes.is_ascii() {
+        return Err(Error::ParseError);
+    }
+    let mut topic = String::new();
+    for b in bytes {
+        topic.push(b as char).map_err(|_| Error::ParseError)?;
+    }
+    Ok(topic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+
+    fn topic(i: usize) -> String<256> {
+        let mut topic = String::new();
+        write!(topic, "topic/{}", i).unwrap();
+        topic
+    }
+
+    /// A table filled to capacity with registered topics 1..=CAPACITY
+    fn full_table() -> Topics {
+        let mut topics = Topics::new();
+        for i in 1..=Topics::CAPACITY {
+            topics.insert(topic(i), TopicIdType::Id, i as u16).unwrap();
+        }
+        topics
+    }
+
+    #[test]
+    fn remove_frees_capacity() {
+        let mut topics = full_table();
+        assert!(matches!(
+            topics.insert(topic(100), TopicIdType::Id, 100),
+            Err(Error::TopicTableFull)
+        ));
+
+        assert_eq!(topics.remove(&topic(3)), Some((TopicIdType::Id, 3)));
+        assert_eq!(topics.remove(&topic(3)), None);
+        topics.insert(topic(100), TopicIdType::Id, 100).unwrap();
+        assert_eq!(topics.get_by_id(TopicIdType::Id, 100).unwrap(), "topic/100");
+        assert!(topics.get_by_topic(&topic(3)).is_none());
+    }
+
+    #[test]
+    fn insert_evicting_skips_kept_and_predefined_topics() {
+        let mut topics = Topics::new();
+        topics.insert(topic(0), TopicIdType::PreDef, 1).unwrap();
+        for i in 1..Topics::CAPACITY {
+            topics.insert(topic(i), TopicIdType::Id, i as u16).unwrap();
+        }
+        let generation = topics.generation();
+        // topic/1 is subscribed, so the oldest evictable one is topic/2
+        let subscribed = |t: &str| t == "topic/1";
+
+        topics.insert_evicting(topic(100), TopicIdType::Id, 100, subscribed).unwrap();
+        assert!(topics.get_by_topic(&topic(2)).is_none());
+        assert!(topics.get_by_topic(&topic(0)).is_some());
+        assert!(topics.get_by_topic(&topic(1)).is_some());
+        assert!(topics.get_by_topic(&topic(100)).is_some());
+        assert_ne!(topics.generation(), generation);
+
+        // Evicting more never touches the kept and predefined topics
+        for i in 101..101 + Topics::CAPACITY {
+            topics.insert_evicting(topic(i), TopicIdType::Id, i as u16, subscribed).unwrap();
+            assert!(topics.get_by_topic(&topic(0)).is_some());
+            assert!(topics.get_by_topic(&topic(1)).is_some());
+        }
+    }
+
+    #[test]
+    fn insert_evicting_fails_when_nothing_can_go() {
+        let mut topics = full_table();
+        assert!(matches!(
+            topics.insert_evicting(topic(100), TopicIdType::Id, 100, |_| true),
+            Err(Error::TopicTableFull)
+        ));
+        assert_eq!(topics.iter().count(), Topics::CAPACITY);
+    }
+
+    #[test]
+    fn insert_evicting_updates_a_known_topic_in_place() {
+        let mut topics = full_table();
+        let generation = topics.generation();
+        topics.insert_evicting(topic(5), TopicIdType::Id, 50, |_| false).unwrap();
+        assert_eq!(topics.get_by_topic(&topic(5)), Some(&(TopicIdType::Id, 50)));
+        assert_eq!(topics.iter().count(), Topics::CAPACITY);
+        assert_eq!(topics.generation(), generation);
+    }
+
+    #[test]
+    fn remap_moves_the_topic_and_bumps_the_generation() {
+        let mut topics = Topics::new();
+        topics.insert(topic(1), TopicIdType::Id, 1).unwrap();
+        let generation = topics.generation();
+
+        topics.remap(TopicIdType::Id, 1, 9).unwrap();
+        assert_eq!(topics.get_by_topic(&topic(1)), Some(&(TopicIdType::Id, 9)));
+        assert!(topics.get_by_id(TopicIdType::Id, 1).is_err());
+        assert_ne!(topics.generation(), generation);
+
+        let generation = topics.generation();
+        assert!(topics.remap(TopicIdType::Id, 1, 10).is_err());
+        assert_eq!(topics.generation(), generation);
+    }
+
+    #[test]
+    fn validate_filter_accepts_whole_level_wildcards() {
+        for filter in ["a/b", "a/+/#", "+", "#", "+/+", "a/#"] {
+            assert!(validate_filter(filter).is_ok(), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn validate_filter_rejects_misplaced_wildcards() {
+        for filter in ["", "#/a", "a/#/b", "a#", "a/b#", "+x", "a/x+/b"] {
+            assert!(matches!(validate_filter(filter), Err(Error::InvalidFilter)), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn validate_filter_accepts_empty_levels() {
+        // MQTT allows zero length levels, only an empty filter is invalid
+        for filter in ["a//b", "/a", "a/", "/", "+//#"] {
+            assert!(validate_filter(filter).is_ok(), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn topic_matches_levels() {
+        let cases = [
+            // Exact match
+            ("a/b", "a/b", true),
+            ("a/b", "a/c", false),
+            ("a/b", "a/b/c", false),
+            ("a/b/c", "a/b", false),
+            // + matches exactly one level
+            ("a/+", "a/b", true),
+            ("a/+", "a/", true),
+            ("a/+", "a", false),
+            ("a/+", "a/b/c", false),
+            ("+/+", "a/b", true),
+            ("a/+/c", "a/b/c", true),
+            ("a/+/c", "a/b/d", false),
+            // # matches zero or more levels
+            ("a/#", "a", true),
+            ("a/#", "a/b", true),
+            ("a/#", "a/b/c", true),
+            ("a/#", "b", false),
+            ("#", "a/b/c", true),
+            ("a/+/#", "a/b", true),
+            ("a/+/#", "a", false),
+            // $ topics only match filters that don't start with a wildcard
+            ("#", "$SYS/uptime", false),
+            ("+/uptime", "$SYS/uptime", false),
+            ("$SYS/#", "$SYS/uptime", true),
+            ("$SYS/+", "$SYS/uptime", true),
+            ("$SYS/uptime", "$SYS/uptime", true),
+            ("$SYS/uptime", "SYS/uptime", false),
+        ];
+        for (filter, topic, matches) in cases {
+            assert_eq!(topic_matches(filter, topic), matches, "{} against {}", filter, topic);
+        }
+    }
+
+    #[test]
+    fn short_topic_round_trip() {
+        for topic in ["ab", "t1", "/x", "  "] {
+            let id = short_topic_id(topic).unwrap();
+            assert_eq!(short_topic_name(id).unwrap(), topic);
+        }
+        assert_eq!(short_topic_id("ab"), Some(0x6162));
+    }
+
+    #[test]
+    fn short_topic_must_be_two_ascii_bytes() {
+        for topic in ["", "a", "abc", "é"] {
+            assert_eq!(short_topic_id(topic), None, "{}", topic);
+        }
+        assert!(matches!(short_topic_name(0x80_61), Err(Error::ParseError)));
+        assert!(matches!(short_topic_name(0x61_FF), Err(Error::ParseError)));
+    }
+}